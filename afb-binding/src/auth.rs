@@ -0,0 +1,285 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Session authorization: AllowPowerOn(true) is only sent once session is authorized.
+ *  free: authorized as soon as car is plugged (legacy behavior)
+ *  api: subcall auth api/verb at plug time, reply {"authorized":true|false}
+ *  event: wait for an auth api event {"connector":uid, "authorized":true|false}
+ *  local: 'auth' verb with a tag matching config allow-list
+ * Authorization belongs to one plug: cleared on plug/unplug, grants without car are ignored.
+ */
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AuthMode {
+    Free,
+    Api,
+    Event,
+    Local,
+}
+
+// action when car requests power before authorization
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AuthTimeout {
+    Wait,
+    Allow,
+    Pause,
+}
+
+#[derive(Clone)]
+pub(crate) struct AuthConfig {
+    pub mode: AuthMode,
+    pub api: &'static str,
+    pub verb: &'static str,
+    pub event: &'static str,
+    pub subscribe: &'static str,
+    pub allow: Vec<String>,
+    pub timeout: u32,
+    pub on_timeout: AuthTimeout,
+}
+
+impl AuthConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jauth = match jconf.optional::<JsoncObj>("auth")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let mode = match jauth.default::<&'static str>("mode", "free")?.to_uppercase().as_str() {
+            "FREE" => AuthMode::Free,
+            "API" => AuthMode::Api,
+            "EVENT" => AuthMode::Event,
+            "LOCAL" => AuthMode::Local,
+            _ => return afb_error!("auth-config-invalid", "auth mode should be free|api|event|local"),
+        };
+
+        let on_timeout = match jauth
+            .default::<&'static str>("timeout_action", "pause")?
+            .to_uppercase()
+            .as_str()
+        {
+            "WAIT" => AuthTimeout::Wait,
+            "ALLOW" => AuthTimeout::Allow,
+            "PAUSE" => AuthTimeout::Pause,
+            _ => {
+                return afb_error!(
+                    "auth-config-invalid",
+                    "auth timeout_action should be wait|allow|pause"
+                )
+            }
+        };
+
+        let mut allow = Vec::new();
+        if let Some(jallow) = jauth.optional::<JsoncObj>("allow")? {
+            for idx in 0..jallow.count()? {
+                allow.push(jallow.index::<String>(idx)?);
+            }
+        }
+
+        let (api, verb, event) = match mode {
+            AuthMode::Api => (
+                jauth.get::<&'static str>("api")?,
+                jauth.get::<&'static str>("verb")?,
+                "",
+            ),
+            AuthMode::Event => (
+                jauth.get::<&'static str>("api")?,
+                "",
+                jauth.get::<&'static str>("event")?,
+            ),
+            _ => ("", "", ""),
+        };
+
+        Ok(AuthConfig {
+            mode,
+            api,
+            verb,
+            event,
+            subscribe: jauth.default::<&'static str>("subscribe", "subscribe")?,
+            allow,
+            timeout: jauth.default::<u32>("timeout", 60000)?,
+            on_timeout,
+        })
+    }
+
+    // api required at binding finalize time
+    pub fn required_api(&self) -> Option<&'static str> {
+        match self.mode {
+            AuthMode::Api | AuthMode::Event => Some(self.api),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn auth_set(state: &Rc<EvseState>, authorized: bool) -> Result<(), AfbError> {
+    let (changed, paused) = {
+        let mut data = state.data.borrow_mut();
+        // a grant never outlives the plug it was given for
+        if authorized && !data.plugged {
+            afb_log_msg!(Notice, None, "{}: authorization ignored, no car", state.uid);
+            return Ok(());
+        }
+        let changed = data.authorized != authorized;
+        data.authorized = authorized;
+        (changed, data.pause == Some(PauseReason::Unauthorized))
+    };
+    state.auth_gen.set(state.auth_gen.get() + 1);
+    if !changed {
+        return Ok(());
+    }
+    state.push(Iec6185Msg::Authorized(authorized));
+    session_authorized(state, authorized);
+
+    if !authorized {
+        return state.withdraw_power();
+    }
+
+    lock_trigger(state, LockTrigger::Auth)?;
+    delay_session(state)?;
+    if paused {
+        // resume restores PWM and power
+        pause_resume(state)?;
+        return Ok(());
+    }
+    state.apply_power()
+}
+
+// new plug, start a fresh authorization (free mode grants it, once per plug)
+pub(crate) fn auth_plugged(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    state.data.borrow_mut().authorized = false;
+    state.auth_gen.set(state.auth_gen.get() + 1);
+    match state.auth.mode {
+        AuthMode::Free => auth_set(state, true),
+        AuthMode::Api => {
+            let query = JsoncObj::new();
+            query.add("connector", state.uid)?;
+            let authorized = match AfbSubCall::call_sync(
+                state.apiv4,
+                state.auth.api,
+                state.auth.verb,
+                query,
+            ) {
+                Ok(reply) => reply.get::<JsoncObj>(0)?.default::<bool>("authorized", false)?,
+                Err(error) => {
+                    afb_log_msg!(Error, None, "{}: auth api fail:{}", state.uid, error);
+                    false
+                }
+            };
+            auth_set(state, authorized)
+        }
+        AuthMode::Event | AuthMode::Local => Ok(()),
+    }
+}
+
+pub(crate) fn auth_unplugged(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    if state.auth.mode == AuthMode::Free {
+        return Ok(());
+    }
+    auth_set(state, false)
+}
+
+struct AuthTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+fn auth_timeout_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<AuthTimerCtx>()?;
+    let state = &ctx.state;
+    {
+        let data = state.data.borrow();
+        if ctx.gen != state.auth_gen.get() || data.authorized || !data.power_rqt {
+            return Ok(());
+        }
+    }
+
+    afb_log_msg!(
+        Warning,
+        None,
+        "{}: authorization timeout action:{:?}",
+        state.uid,
+        state.auth.on_timeout
+    );
+    state.push(Iec6185Msg::Error("auth-timeout".to_string()));
+
+    match state.auth.on_timeout {
+        AuthTimeout::Wait => Ok(()),
+        AuthTimeout::Allow => auth_set(state, true),
+        AuthTimeout::Pause => {
+            if state.data.borrow().pause.is_some() {
+                return Ok(());
+            }
+            pause_start(state, PauseReason::Unauthorized)
+        }
+    }
+}
+
+// car requested power, PWM stays at advertised limit while waiting for authorization
+pub(crate) fn auth_power_rqt(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    if state.data.borrow().authorized || state.auth.timeout == 0 {
+        return Ok(());
+    }
+
+    AfbTimer::new("auth-timeout")
+        .set_period(state.auth.timeout)
+        .set_decount(1)
+        .set_callback(auth_timeout_cb)
+        .set_context(AuthTimerCtx {
+            state: state.clone(),
+            gen: state.auth_gen.get(),
+        })
+        .start()?;
+    Ok(())
+}
+
+// local allow-list check
+pub(crate) fn auth_tag(state: &Rc<EvseState>, tag: &str) -> Result<(), AfbError> {
+    if state.auth.mode != AuthMode::Local {
+        return afb_error!("auth-invalid-mode", "tag authorization requires local mode");
+    }
+    if !state.data.borrow().plugged {
+        return afb_error!("auth-not-plugged", "tag authorization requires a plugged car");
+    }
+    if !state.auth.allow.iter().any(|value| value == tag) {
+        afb_log_msg!(Notice, None, "{}: tag:{} rejected", state.uid, tag);
+        return afb_error!("auth-rejected", "tag not in allow-list");
+    }
+    auth_set(state, true)
+}
+
+pub(crate) struct AuthEvtCtx {
+    pub state: Rc<EvseState>,
+}
+
+// auth api event {"connector":uid, "authorized":true|false}
+pub(crate) fn auth_event_cb(
+    _evt: &AfbEventMsg,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<AuthEvtCtx>()?;
+    let jevent = args.get::<JsoncObj>(0)?;
+    // auth event is shared by every connector
+    if jevent.get::<&str>("connector")? != ctx.state.uid {
+        return Ok(());
+    }
+    let authorized = jevent.default::<bool>("authorized", false)?;
+    auth_set(&ctx.state, authorized)
+}
@@ -19,35 +19,139 @@
  *  interfacing through kernel RPMSG the firmware running in the MCU/M4 cortex.
  */
 
+use std::rc::Rc;
+
 use crate::prelude::*;
 use afbv4::prelude::*;
 use rpmsg::prelude::*;
 use typesv4::prelude::*;
 
+// per connector configuration (legacy single connector config uses binding top level object)
 pub(crate) struct ApiUserData {
     pub uid: &'static str,
+    // verb/event prefix, only set when config holds a connectors array
+    pub prefix: Option<&'static str>,
+    pub event: &'static str,
     pub cdev: Option<&'static str>,
     pub eptname: &'static str,
-    pub lock_api: &'static str,
-    pub lock_verb: &'static str,
     pub rport: i32,
-    pub tic: u32,
+    // upper bound for imax verb and budget share (A)
+    pub max_current: u32,
+    // budget priority policy, higher first
+    pub priority: u32,
+    pub pause_mode: PauseMode,
+    pub pause_timeout: u32,
+    pub admin_permission: Option<&'static str>,
+    pub wakeup: WakeupConfig,
+    pub replug: ReplugConfig,
+    pub phases: PhasesConfig,
+    pub lock: LockPolicy,
+    pub auth: AuthConfig,
+    pub store: Option<StoreConfig>,
+    pub envelope: bool,
+    pub history: HistoryConfig,
+    pub external_limit: ExtLimitConfig,
+    pub profile: ProfileCtx,
+    pub random_delay: DelayConfig,
+    pub surplus: SurplusConfig,
+    pub meter: Option<MeterConfig>,
+    pub thermal: Option<ThermalConfig>,
+}
+
+impl ApiUserData {
+    fn from_jsonc(
+        jconn: &JsoncObj,
+        uid: &'static str,
+        prefix: Option<&'static str>,
+        admin_permission: Option<&'static str>,
+    ) -> Result<Self, AfbError> {
+        let event = jconn.default::<&'static str>("event", "iec")?;
+
+        Ok(ApiUserData {
+            uid,
+            prefix,
+            event: prefixed_name(prefix, event),
+            cdev: jconn.optional::<&'static str>("cdev")?,
+            eptname: jconn.default::<&'static str>("eptname", "tux-evse-rmsg")?,
+            rport: jconn.default::<i32>("rport", 14)?,
+            max_current: jconn.default::<u32>("max_current", 80)?,
+            priority: jconn.default::<u32>("priority", 0)?,
+            pause_mode: PauseMode::parse(jconn.default::<&'static str>("pause_mode", "zero")?)?,
+            pause_timeout: jconn.default::<u32>("pause_timeout", 5000)?,
+            admin_permission,
+            wakeup: WakeupConfig::from_jsonc(jconn)?,
+            replug: ReplugConfig::from_jsonc(jconn)?,
+            phases: PhasesConfig::from_jsonc(jconn)?,
+            lock: LockPolicy::from_jsonc(jconn)?,
+            auth: AuthConfig::from_jsonc(jconn)?,
+            store: StoreConfig::from_jsonc(jconn)?,
+            envelope: jconn.default::<bool>("envelope", false)?,
+            history: HistoryConfig::from_jsonc(jconn)?,
+            external_limit: ExtLimitConfig::from_jsonc(jconn)?,
+            profile: ProfileCtx::from_jsonc(jconn)?,
+            random_delay: DelayConfig::from_jsonc(jconn)?,
+            surplus: SurplusConfig::from_jsonc(jconn)?,
+            meter: MeterConfig::from_jsonc(jconn)?,
+            thermal: ThermalConfig::from_jsonc(jconn)?,
+        })
+    }
 }
 
-fn to_static_str(value: String) -> &'static str {
+pub(crate) fn to_static_str(value: String) -> &'static str {
     Box::leak(value.into_boxed_str())
 }
 
-struct ApiCtxData{}
+// 'c1/pwm' when connector has a prefix, 'pwm' otherwise
+pub(crate) fn prefixed_name(prefix: Option<&str>, name: &'static str) -> &'static str {
+    match prefix {
+        Some(prefix) => to_static_str(format!("{}/{}", prefix, name)),
+        None => name,
+    }
+}
+
+struct ApiCtxData {
+    states: Vec<Rc<EvseState>>,
+}
 
 impl AfbApiControls for ApiCtxData {
 
     fn start(&mut self, api: &AfbApi) -> Result<(), AfbError> {
         // place here any required api subscription
         afb_log_msg!(Debug, None, "start apiv4={:?}", api.get_apiv4());
+        let mut subscribed = Vec::new();
+        for state in &self.states {
+            let mut sources = Vec::new();
+            if state.auth.mode == AuthMode::Event {
+                sources.push((state.auth.api, state.auth.subscribe));
+            }
+            if let Some(meter) = state.surplus.config.required_api() {
+                sources.push((meter, state.surplus.config.subscribe));
+            }
+            if let Some(meter) = &state.meter {
+                sources.push((meter.config.api, meter.config.subscribe));
+            }
+            if let Some(thermal) = &state.thermal {
+                sources.extend(thermal.config.apis());
+            }
+            for (source, verb) in sources {
+                if subscribed.contains(&source) {
+                    continue;
+                }
+                AfbSubCall::call_sync(api.get_apiv4(), source, verb, true)?;
+                subscribed.push(source);
+            }
+        }
         Ok(())
     }
 
+    // binder shutdown, never leave a cable locked
+    fn exit(&mut self, _api: &AfbApi, code: i32) -> i32 {
+        for state in &self.states {
+            lock_shutdown(state);
+        }
+        code
+    }
+
     // mandatory for downcasting back to custom api data object
     fn as_any(&mut self) -> &mut dyn Any {
         self
@@ -66,41 +170,94 @@ pub fn binding_init(rootv4: AfbApiV4, jconf: JsoncObj) -> Result<&'static AfbApi
     let uid = to_static_str(jconf.get::<String>("uid")?);
     let api = jconf.default::<&'static str>("api",uid)?;
     let info = jconf.default::<&'static str>("info","")?;
-    let cdev = jconf.optional::<&'static str>("cdev")?;
     let socname = jconf.optional::<&'static str>("socname")?;
-    let eptname = jconf.default::<&'static str>("eptname","tux-evse-rmsg")?;
-    let rport = jconf.default::<i32>("rport", 14)?;
     let tic = jconf.default::<u32>("tic", 5000)?;
-    let lock_api = jconf.get::<&'static str>("lock_api")?;
-    let lock_verb = jconf.get::<&'static str>("lock_verb")?;
-
-    let config = ApiUserData {
-        uid,
-        cdev,
-        rport,
-        eptname,
-        tic,
-        lock_api,
-        lock_verb,
-    };
+    let admin_permission = jconf.optional::<&'static str>("admin_permission")?;
+    let budget = BudgetConfig::from_jsonc(&jconf)?;
+
+    // dual socket chargers: one entry per rpmsg endpoint, verbs/events prefixed with connector uid
+    let mut configs = Vec::new();
+    match jconf.optional::<JsoncObj>("connectors")? {
+        None => configs.push(ApiUserData::from_jsonc(&jconf, uid, None, admin_permission)?),
+        Some(jconns) => {
+            for idx in 0..jconns.count()? {
+                let jconn = jconns.index::<JsoncObj>(idx)?;
+                let cuid = to_static_str(jconn.get::<String>("uid")?);
+                if configs.iter().any(|config: &ApiUserData| config.uid == cuid) {
+                    return afb_error!("connector-config-invalid", "duplicated connector uid:{}", cuid);
+                }
+                let config = ApiUserData::from_jsonc(&jconn, cuid, Some(cuid), admin_permission)?;
+                if configs.iter().any(|other: &ApiUserData| {
+                    other.eptname == config.eptname && other.rport == config.rport
+                }) {
+                    return afb_error!(
+                        "connector-config-invalid",
+                        "connector:{} endpoint {}:{} already in use",
+                        cuid,
+                        config.eptname,
+                        config.rport
+                    );
+                }
+                configs.push(config);
+            }
+        }
+    }
+    if configs.is_empty() {
+        return afb_error!("connector-config-invalid", "connectors array is empty");
+    }
 
     // initialization of ti rpm_char_lib should be done once at initialization
     ti_init(socname)?;
 
     // create a new api
-    let api = AfbApi::new(api)
-        .set_info(info)
-        .set_callback(Box::new(ApiCtxData{}));
+    let api = AfbApi::new(api).set_info(info);
 
     if let Ok(value) = jconf.get::<String>("permission") {
         api.set_permission(AfbPermission::new(to_static_str(value)));
     };
 
     // register verbs and events
-    register(rootv4, api, &config)?;
+    let mut states = Vec::new();
+    for config in &configs {
+        states.push(register(rootv4, api, config)?);
+    }
+
+    // site budget is shared by every connector
+    if let Some(budget) = budget {
+        let budget = Rc::new(PowerBudget::new(budget, &states));
+        for state in &states {
+            let _ = state.budget.set(budget.clone());
+        }
+    }
+
+    // a single heartbeat timer serves every endpoint
+    if tic > 0 {
+        heartbeat_start(uid, tic, states.iter().map(|state| state.dev.clone()).collect())?;
+    }
+    api.set_callback(Box::new(ApiCtxData { states }));
 
     // finalize api
-    api.require_api(lock_api);
+    let mut apis = Vec::new();
+    for config in &configs {
+        apis.extend(LockCtx::apis(&config.lock));
+        if let Some(auth_api) = config.auth.required_api() {
+            apis.push(auth_api);
+        }
+        if let Some(meter_api) = config.surplus.required_api() {
+            apis.push(meter_api);
+        }
+        if let Some(meter) = &config.meter {
+            apis.push(meter.api);
+        }
+        if let Some(thermal) = &config.thermal {
+            apis.extend(thermal.apis().into_iter().map(|(api, _)| api));
+        }
+    }
+    apis.sort();
+    apis.dedup();
+    for required in apis {
+        api.require_api(required);
+    }
     let api= api.finalize()?;
 
     Ok(api)
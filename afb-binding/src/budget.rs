@@ -0,0 +1,234 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Shared site power budget: total current is split between active connectors
+ *  (plugged and car not done) each time one of them plugs, unplugs or stops.
+ *  Connectors that cannot get the minimum current are paused until reallocation, cars
+ *  done charging keep their last offer so they can ask for power again.
+ */
+use std::rc::{Rc, Weak};
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BudgetPolicy {
+    // same share for everyone (within cable limits)
+    Equal,
+    // first plugged car takes what it can
+    First,
+    // higher connector priority first, then plug order
+    Priority,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct BudgetConfig {
+    pub total: u32,
+    pub min: u32,
+    pub policy: BudgetPolicy,
+}
+
+impl BudgetConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Option<Self>, AfbError> {
+        let jbudget = match jconf.optional::<JsoncObj>("budget")? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let policy = match jbudget
+            .default::<&'static str>("policy", "equal")?
+            .to_uppercase()
+            .as_str()
+        {
+            "EQUAL" => BudgetPolicy::Equal,
+            "FIRST" => BudgetPolicy::First,
+            "PRIORITY" => BudgetPolicy::Priority,
+            _ => {
+                return afb_error!(
+                    "budget-config-invalid",
+                    "budget policy should be equal|first|priority"
+                )
+            }
+        };
+
+        let min = jbudget.default::<u32>("min", IEC_MIN_CURRENT)?;
+        if min < IEC_MIN_CURRENT {
+            return afb_error!(
+                "budget-config-invalid",
+                "budget min should be >= {}",
+                IEC_MIN_CURRENT
+            );
+        }
+
+        Ok(Some(BudgetConfig {
+            total: jbudget.get::<u32>("total")?,
+            min,
+            policy,
+        }))
+    }
+}
+
+struct BudgetCandidate {
+    idx: usize,
+    cap: u32,
+    priority: u32,
+    plugged: u64,
+}
+
+// return allocated current per candidate (0 when minimum cannot be granted)
+fn budget_split(config: &BudgetConfig, mut candidates: Vec<BudgetCandidate>) -> Vec<(usize, u32)> {
+    match config.policy {
+        BudgetPolicy::Priority => candidates.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then(a.plugged.cmp(&b.plugged))
+        }),
+        _ => candidates.sort_by_key(|candidate| candidate.plugged),
+    }
+
+    let mut shares = Vec::new();
+    let mut remaining = config.total;
+    match config.policy {
+        BudgetPolicy::Equal => {
+            // first plugged connectors are admitted while everyone gets the minimum
+            let admitted = candidates.len().min((config.total / config.min) as usize);
+            for candidate in candidates.drain(admitted..) {
+                shares.push((candidate.idx, 0));
+            }
+
+            // water filling: smallest caps first, leftover goes to the others
+            candidates.sort_by_key(|candidate| candidate.cap);
+            let count = candidates.len() as u32;
+            for (rank, candidate) in candidates.iter().enumerate() {
+                let share = remaining / (count - rank as u32);
+                let give = candidate.cap.min(share);
+                remaining -= give;
+                shares.push((candidate.idx, give));
+            }
+        }
+        BudgetPolicy::First | BudgetPolicy::Priority => {
+            for candidate in candidates {
+                let give = candidate.cap.min(remaining);
+                if give < config.min {
+                    shares.push((candidate.idx, 0));
+                } else {
+                    remaining -= give;
+                    shares.push((candidate.idx, give));
+                }
+            }
+        }
+    }
+    shares
+}
+
+pub(crate) struct PowerBudget {
+    config: BudgetConfig,
+    states: Vec<Weak<EvseState>>,
+}
+
+impl PowerBudget {
+    pub fn new(config: BudgetConfig, states: &[Rc<EvseState>]) -> Self {
+        PowerBudget {
+            config,
+            states: states.iter().map(Rc::downgrade).collect(),
+        }
+    }
+
+    pub fn allocate(&self) -> Result<(), AfbError> {
+        let states: Vec<Rc<EvseState>> = self.states.iter().filter_map(Weak::upgrade).collect();
+
+        let mut candidates = Vec::new();
+        for (idx, state) in states.iter().enumerate() {
+            let data = state.data.borrow();
+            if !data.plugged || data.ev_done {
+                continue;
+            }
+            // unknown cable rating (PP not yet decoded) falls back to connector max
+            let cap = match data.imax {
+                0 => state.max_current,
+                imax => imax.min(state.max_current),
+            };
+            candidates.push(BudgetCandidate {
+                idx,
+                cap,
+                priority: state.priority,
+                plugged: match &data.session.current {
+                    Some(session) => session.plugged,
+                    None => 0,
+                },
+            });
+        }
+
+        let shares = budget_split(&self.config, candidates);
+        for (idx, state) in states.iter().enumerate() {
+            let share = shares
+                .iter()
+                .find(|(candidate, _)| *candidate == idx)
+                .map(|(_, share)| *share);
+            budget_apply(state, share, self.config.min)?;
+        }
+        Ok(())
+    }
+}
+
+fn budget_apply(state: &Rc<EvseState>, share: Option<u32>, min: u32) -> Result<(), AfbError> {
+    let (plugged, pause) = {
+        let data = state.data.borrow();
+        (data.plugged, data.pause)
+    };
+
+    let denied = matches!(share, Some(value) if value < min);
+    state.data.borrow_mut().budget_denied = denied;
+
+    // car left or done: out of the share, car done keeps last duty so it can ask again
+    let share = match share {
+        Some(value) => value,
+        None => {
+            if !plugged {
+                state.set_limit(LimitSource::Budget, None)?;
+            }
+            if pause == Some(PauseReason::Budget) {
+                pause_resume(state)?;
+            }
+            return Ok(());
+        }
+    };
+
+    if share >= min {
+        afb_log_msg!(Debug, None, "{}: budget share:{}A", state.uid, share);
+        state.set_limit(LimitSource::Budget, Some(share))?;
+        if pause == Some(PauseReason::Budget) {
+            pause_resume(state)?;
+        }
+        return Ok(());
+    }
+
+    // no 0A limit (0% duty is state F), pause duty keeps car able to request power
+    if pause.is_none() {
+        afb_log_msg!(Notice, None, "{}: not enough budget, pausing", state.uid);
+        pause_start(state, PauseReason::Budget)?;
+    }
+    Ok(())
+}
+
+// reallocate site budget after a connector state change
+pub(crate) fn budget_update(state: &EvseState) -> Result<(), AfbError> {
+    match state.budget.get() {
+        Some(budget) => budget.allocate(),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Fine-grained event channels: every message is published on its own channel
+ *  and on the legacy 'iec' event (except mcu heartbeats, too verbose for it).
+ */
+use std::cell::Cell;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EvseChannel {
+    Plug,
+    Power,
+    Cable,
+    Relay,
+    Error,
+    Slac,
+    Mcu,
+    Limit,
+    Session,
+    Ocpp,
+}
+
+const CHANNELS: [EvseChannel; 10] = [
+    EvseChannel::Plug,
+    EvseChannel::Power,
+    EvseChannel::Cable,
+    EvseChannel::Relay,
+    EvseChannel::Error,
+    EvseChannel::Slac,
+    EvseChannel::Mcu,
+    EvseChannel::Limit,
+    EvseChannel::Session,
+    EvseChannel::Ocpp,
+];
+
+impl EvseChannel {
+    pub fn parse(value: &str) -> Result<Self, AfbError> {
+        let channel = match value.to_uppercase().as_str() {
+            "PLUG" => EvseChannel::Plug,
+            "POWER" => EvseChannel::Power,
+            "CABLE" => EvseChannel::Cable,
+            "RELAY" => EvseChannel::Relay,
+            "ERROR" => EvseChannel::Error,
+            "SLAC" => EvseChannel::Slac,
+            "MCU" | "HEARTBEAT" => EvseChannel::Mcu,
+            "LIMIT" => EvseChannel::Limit,
+            "SESSION" => EvseChannel::Session,
+            "OCPP" => EvseChannel::Ocpp,
+            _ => {
+                return afb_error!(
+                    "channel-invalid",
+                    "event:{} should be plug|power|cable|relay|error|slac|mcu|limit|session|ocpp",
+                    value
+                )
+            }
+        };
+        Ok(channel)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvseChannel::Plug => "plug",
+            EvseChannel::Power => "power",
+            EvseChannel::Cable => "cable",
+            EvseChannel::Relay => "relay",
+            EvseChannel::Error => "error",
+            EvseChannel::Slac => "slac",
+            EvseChannel::Mcu => "mcu",
+            EvseChannel::Limit => "limit",
+            EvseChannel::Session => "session",
+            EvseChannel::Ocpp => "ocpp",
+        }
+    }
+
+    pub fn of(msg: &Iec6185Msg) -> Self {
+        match msg {
+            Iec6185Msg::Plugged(_)
+            | Iec6185Msg::Replug(_)
+            | Iec6185Msg::WakeUp(_)
+            | Iec6185Msg::Locked(_) => EvseChannel::Plug,
+            Iec6185Msg::PowerRqt(_)
+            | Iec6185Msg::Paused(_)
+            | Iec6185Msg::Resumed(_)
+            | Iec6185Msg::Authorized(_)
+            | Iec6185Msg::RandomDelay(_) => EvseChannel::Power,
+            Iec6185Msg::CableImax(_) => EvseChannel::Cable,
+            Iec6185Msg::RelayOn(_) | Iec6185Msg::Phases(_) => EvseChannel::Relay,
+            Iec6185Msg::Error(_) | Iec6185Msg::LockFault(_) => EvseChannel::Error,
+            Iec6185Msg::Slac(_) => EvseChannel::Slac,
+            Iec6185Msg::Heartbeat(_) | Iec6185Msg::Firmware(_) => EvseChannel::Mcu,
+            Iec6185Msg::Limit(_) | Iec6185Msg::LimitFailsafe(_) | Iec6185Msg::Overtemp(_) => {
+                EvseChannel::Limit
+            }
+            Iec6185Msg::SessionStart(_)
+            | Iec6185Msg::SessionUpdate(_)
+            | Iec6185Msg::SessionEnd(_) => EvseChannel::Session,
+            Iec6185Msg::Ocpp(_) => EvseChannel::Ocpp,
+        }
+    }
+}
+
+// one event per channel, indexed by EvseChannel value
+pub(crate) struct EvseChannels {
+    events: [&'static AfbEvent; CHANNELS.len()],
+    // last envelope sequence number published on each channel
+    seqs: [Cell<u64>; CHANNELS.len()],
+}
+
+impl EvseChannels {
+    pub fn new(prefix: Option<&str>) -> Self {
+        EvseChannels {
+            events: CHANNELS.map(|channel| AfbEvent::new(prefixed_name(prefix, channel.name()))),
+            seqs: CHANNELS.map(|_| Cell::new(0)),
+        }
+    }
+
+    // subscribers detect lost events from gaps within one channel
+    pub fn next_seq(&self, channel: EvseChannel) -> u64 {
+        let seq = &self.seqs[channel as usize];
+        seq.set(seq.get() + 1);
+        seq.get()
+    }
+
+    pub fn get(&self, channel: EvseChannel) -> &'static AfbEvent {
+        self.events[channel as usize]
+    }
+
+    pub fn events(&self) -> &[&'static AfbEvent] {
+        &self.events
+    }
+}
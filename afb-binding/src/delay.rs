@@ -0,0 +1,175 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Randomised delay (UK Smart Charge Point regulations like): AllowPowerOn at session
+ *  start and grid driven limit increases (external/profile) are held for a random time
+ *  within [0, max] seconds. Limit decreases, external failsafe and local safety limits
+ *  (budget) always apply immediately.
+ */
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy)]
+pub(crate) struct DelayConfig {
+    // upper bound in seconds, 0 disables random delay
+    pub max: u32,
+    // delay first AllowPowerOn of a session
+    pub on_start: bool,
+    // delay grid driven limit changes
+    pub on_limit: bool,
+}
+
+impl DelayConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jdelay = match jconf.optional::<JsoncObj>("random_delay")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        Ok(DelayConfig {
+            max: jdelay.default::<u32>("max", 0)?,
+            on_start: jdelay.default::<bool>("on_start", true)?,
+            on_limit: jdelay.default::<bool>("on_limit", true)?,
+        })
+    }
+}
+
+pub(crate) struct DelayCtx {
+    pub config: DelayConfig,
+    until: Cell<Option<Instant>>,
+    gen: Cell<u32>,
+}
+
+impl DelayCtx {
+    pub fn new(config: DelayConfig) -> Self {
+        DelayCtx {
+            config,
+            until: Cell::new(None),
+            gen: Cell::new(0),
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.until.get().is_some()
+    }
+
+    // remaining delay in ms
+    pub fn remaining(&self) -> Option<u64> {
+        self.until
+            .get()
+            .map(|until| until.saturating_duration_since(Instant::now()).as_millis() as u64)
+    }
+}
+
+// no rand crate, std hasher keys are randomly seeded per process
+fn random_secs(max: u32) -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now_ms());
+    (hasher.finish() % (max as u64 + 1)) as u32
+}
+
+struct DelayTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+fn delay_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<DelayTimerCtx>()?;
+    if ctx.gen != ctx.state.delay.gen.get() {
+        return Ok(());
+    }
+    afb_log_msg!(Notice, None, "{}: random delay done", ctx.state.uid);
+    delay_done(&ctx.state)
+}
+
+// release held limits and power
+fn delay_done(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    state.delay.gen.set(state.delay.gen.get() + 1);
+    if state.delay.until.take().is_none() {
+        return Ok(());
+    }
+    state.push(Iec6185Msg::RandomDelay(0));
+    state.limits_release()?;
+    state.apply_power()
+}
+
+// start a delay unless one is already running (pending changes share its expiry)
+pub(crate) fn delay_start(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let delay = &state.delay;
+    if delay.config.max == 0 || delay.active() {
+        return Ok(());
+    }
+
+    let secs = random_secs(delay.config.max);
+    let gen = delay.gen.get() + 1;
+    delay.gen.set(gen);
+    afb_log_msg!(Notice, None, "{}: random delay {}s", state.uid, secs);
+    if secs == 0 {
+        return Ok(());
+    }
+
+    delay
+        .until
+        .set(Some(Instant::now() + Duration::from_secs(secs as u64)));
+    state.push(Iec6185Msg::RandomDelay(secs));
+    AfbTimer::new("random-delay")
+        .set_period(secs * 1000)
+        .set_decount(1)
+        .set_callback(delay_timer_cb)
+        .set_context(DelayTimerCtx {
+            state: state.clone(),
+            gen,
+        })
+        .start()?;
+    Ok(())
+}
+
+// session start once car is plugged and authorized
+pub(crate) fn delay_session(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let ready = {
+        let data = state.data.borrow();
+        data.plugged && data.authorized
+    };
+    if !ready || !state.delay.config.on_start {
+        return Ok(());
+    }
+    delay_start(state)
+}
+
+// operator override: apply everything now
+pub(crate) fn delay_cancel(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    delay_done(state)
+}
+
+// car left: stop timer and keep held limits for next session, pwm and power stay off
+pub(crate) fn delay_unplugged(state: &Rc<EvseState>) {
+    state.delay.gen.set(state.delay.gen.get() + 1);
+    if state.delay.until.take().is_none() {
+        return;
+    }
+    {
+        let mut data = state.data.borrow_mut();
+        data.limits = data.limits_next.clone();
+    }
+    state.push(Iec6185Msg::RandomDelay(0));
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * External limit from an energy manager (EEBus LPC like): each 'limit' call sets
+ *  the limit for duration_s and refreshes the keep-alive. When the controller stops
+ *  talking for 'timeout' seconds the failsafe current applies until next call.
+ */
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy)]
+pub(crate) struct ExtLimitConfig {
+    // current applied when controller is lost (A)
+    pub failsafe: u32,
+    // keep-alive timeout in seconds, 0 disables failsafe
+    pub timeout: u32,
+}
+
+impl ExtLimitConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jlimit = match jconf.optional::<JsoncObj>("external_limit")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let config = ExtLimitConfig {
+            failsafe: jlimit.default::<u32>("failsafe", 6)?,
+            timeout: jlimit.default::<u32>("timeout", 0)?,
+        };
+        if config.failsafe < IEC_MIN_CURRENT {
+            return afb_error!(
+                "ext-limit-config-invalid",
+                "external_limit failsafe should be >= {}A",
+                IEC_MIN_CURRENT
+            );
+        }
+        // timer period is in ms
+        if config.timeout.checked_mul(1000).is_none() {
+            return afb_error!(
+                "ext-limit-config-invalid",
+                "external_limit timeout:{}s out of range",
+                config.timeout
+            );
+        }
+        Ok(config)
+    }
+}
+
+pub(crate) struct ExtLimitCtx {
+    pub config: ExtLimitConfig,
+    pub failsafe: Cell<bool>,
+    expiry_gen: Cell<u32>,
+    alive_gen: Cell<u32>,
+}
+
+impl ExtLimitCtx {
+    pub fn new(config: ExtLimitConfig) -> Self {
+        ExtLimitCtx {
+            config,
+            failsafe: Cell::new(false),
+            expiry_gen: Cell::new(0),
+            alive_gen: Cell::new(0),
+        }
+    }
+}
+
+struct ExtLimitTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+fn ext_expiry_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ExtLimitTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.ext_limit.expiry_gen.get() || state.ext_limit.failsafe.get() {
+        return Ok(());
+    }
+    afb_log_msg!(Notice, None, "{}: external limit expired", state.uid);
+    state.set_limit(LimitSource::External, None)
+}
+
+fn ext_alive_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ExtLimitTimerCtx>()?;
+    let state = &ctx.state;
+    let ext = &state.ext_limit;
+    if ctx.gen != ext.alive_gen.get() || ext.failsafe.get() {
+        return Ok(());
+    }
+
+    afb_log_msg!(
+        Warning,
+        None,
+        "{}: no external limit within {}s, failsafe {}A",
+        state.uid,
+        ext.config.timeout,
+        ext.config.failsafe
+    );
+    ext.failsafe.set(true);
+    // failsafe protects the grid, never held by random delay
+    state.set_limit_now(LimitSource::External, Some(ext.config.failsafe))?;
+    state.push(Iec6185Msg::LimitFailsafe(true));
+    Ok(())
+}
+
+// (re)arm keep-alive watchdog
+pub(crate) fn ext_limit_watch(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let ext = &state.ext_limit;
+    let gen = ext.alive_gen.get() + 1;
+    ext.alive_gen.set(gen);
+    if ext.config.timeout == 0 {
+        return Ok(());
+    }
+
+    AfbTimer::new("ext-limit-alive")
+        .set_period(ext.config.timeout * 1000)
+        .set_decount(1)
+        .set_callback(ext_alive_cb)
+        .set_context(ExtLimitTimerCtx {
+            state: state.clone(),
+            gen,
+        })
+        .start()?;
+    Ok(())
+}
+
+// amps None clears the limit, duration 0 keeps it until next call
+pub(crate) fn ext_limit_set(
+    state: &Rc<EvseState>,
+    amps: Option<u32>,
+    duration: u32,
+) -> Result<(), AfbError> {
+    let period = match duration.checked_mul(1000) {
+        Some(value) => value,
+        None => return afb_error!("ext-limit-invalid", "duration_s:{} out of range", duration),
+    };
+    let ext = &state.ext_limit;
+    let gen = ext.expiry_gen.get() + 1;
+    ext.expiry_gen.set(gen);
+
+    if ext.failsafe.get() {
+        afb_log_msg!(Notice, None, "{}: external controller back", state.uid);
+        ext.failsafe.set(false);
+        state.push(Iec6185Msg::LimitFailsafe(false));
+    }
+    ext_limit_watch(state)?;
+    state.set_limit(LimitSource::External, amps)?;
+
+    if amps.is_some() && duration > 0 {
+        AfbTimer::new("ext-limit-expiry")
+            .set_period(period)
+            .set_decount(1)
+            .set_callback(ext_expiry_cb)
+            .set_context(ExtLimitTimerCtx {
+                state: state.clone(),
+                gen,
+            })
+            .start()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * In memory ring buffer of the last decoded firmware events and published messages,
+ *  returned by 'history' verb to clients that were not subscribed at the time.
+ */
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use serde::Serialize;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy)]
+pub(crate) struct HistoryConfig {
+    // max entries, 0 disables history
+    pub size: usize,
+    // mcu heartbeats quickly flush anything else
+    pub heartbeat: bool,
+}
+
+impl HistoryConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jhistory = match jconf.optional::<JsoncObj>("history")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        Ok(HistoryConfig {
+            size: jhistory.default::<u32>("size", 200)? as usize,
+            heartbeat: jhistory.default::<bool>("heartbeat", false)?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HistoryKind {
+    Firmware,
+    Published,
+}
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    ts: u64,
+    kind: HistoryKind,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msg: Option<Iec6185Msg>,
+}
+
+pub(crate) struct HistoryQuery<'a> {
+    pub kind: Option<HistoryKind>,
+    pub channel: Option<&'a str>,
+    pub from: u64,
+    pub to: u64,
+}
+
+pub(crate) struct EvseHistory {
+    config: HistoryConfig,
+    entries: RefCell<VecDeque<HistoryEntry>>,
+}
+
+// variant name only, payload is kept within msg
+fn msg_name(msg: &Iec6185Msg) -> &'static str {
+    match msg {
+        Iec6185Msg::Plugged(_) => "Plugged",
+        Iec6185Msg::PowerRqt(_) => "PowerRqt",
+        Iec6185Msg::CableImax(_) => "CableImax",
+        Iec6185Msg::RelayOn(_) => "RelayOn",
+        Iec6185Msg::Error(_) => "Error",
+        Iec6185Msg::Paused(_) => "Paused",
+        Iec6185Msg::Resumed(_) => "Resumed",
+        Iec6185Msg::WakeUp(_) => "WakeUp",
+        Iec6185Msg::Replug(_) => "Replug",
+        Iec6185Msg::Phases(_) => "Phases",
+        Iec6185Msg::Locked(_) => "Locked",
+        Iec6185Msg::LockFault(_) => "LockFault",
+        Iec6185Msg::Authorized(_) => "Authorized",
+        Iec6185Msg::SessionStart(_) => "SessionStart",
+        Iec6185Msg::SessionUpdate(_) => "SessionUpdate",
+        Iec6185Msg::SessionEnd(_) => "SessionEnd",
+        Iec6185Msg::Slac(_) => "Slac",
+        Iec6185Msg::Heartbeat(_) => "Heartbeat",
+        Iec6185Msg::Firmware(_) => "Firmware",
+        Iec6185Msg::Limit(_) => "Limit",
+        Iec6185Msg::LimitFailsafe(_) => "LimitFailsafe",
+        Iec6185Msg::RandomDelay(_) => "RandomDelay",
+        Iec6185Msg::Overtemp(_) => "Overtemp",
+        Iec6185Msg::Ocpp(_) => "Ocpp",
+    }
+}
+
+impl EvseHistory {
+    pub fn new(config: HistoryConfig) -> Self {
+        EvseHistory {
+            config,
+            entries: RefCell::new(VecDeque::with_capacity(config.size)),
+        }
+    }
+
+    fn add(&self, entry: HistoryEntry) {
+        if self.config.size == 0 {
+            return;
+        }
+        let mut entries = self.entries.borrow_mut();
+        if entries.len() == self.config.size {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn firmware(&self, msg: &EventMsg) {
+        let name = match msg {
+            EventMsg::Evt(iec) => iec.as_str_name().to_string(),
+            EventMsg::Heartbeat() => {
+                if !self.config.heartbeat {
+                    return;
+                }
+                "HEARTBEAT".to_string()
+            }
+            EventMsg::Phases(count) => format!("PHASES:{}", count),
+            EventMsg::Lock(locked) => format!("LOCK:{}", locked),
+            EventMsg::Version(version) => format!("VERSION:{}", version),
+            EventMsg::Err(error) => format!("DECODE-ERROR:{}", error),
+        };
+        self.add(HistoryEntry {
+            ts: now_ms(),
+            kind: HistoryKind::Firmware,
+            name,
+            channel: None,
+            msg: None,
+        });
+    }
+
+    pub fn published(&self, channel: EvseChannel, msg: &Iec6185Msg) {
+        if channel == EvseChannel::Mcu && !self.config.heartbeat {
+            return;
+        }
+        self.add(HistoryEntry {
+            ts: now_ms(),
+            kind: HistoryKind::Published,
+            name: msg_name(msg).to_string(),
+            channel: Some(channel.name()),
+            msg: Some(msg.clone()),
+        });
+    }
+
+    // matching entries, oldest first, as a json array string
+    pub fn query(&self, query: &HistoryQuery) -> Result<String, AfbError> {
+        let entries = self.entries.borrow();
+        let selected: Vec<&HistoryEntry> = entries
+            .iter()
+            .filter(|entry| entry.ts >= query.from && entry.ts <= query.to)
+            .filter(|entry| match query.kind {
+                Some(kind) => entry.kind == kind,
+                None => true,
+            })
+            .filter(|entry| match query.channel {
+                Some(channel) => entry.channel == Some(channel),
+                None => true,
+            })
+            .collect();
+
+        match serde_json::to_string(&selected) {
+            Ok(value) => Ok(value),
+            Err(error) => afb_error!("history-encode-fail", "{}", error),
+        }
+    }
+}
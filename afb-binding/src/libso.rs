@@ -24,8 +24,88 @@ mod verbs;
 #[path = "binding.rs"]
 mod binding;
 
+#[path = "state.rs"]
+mod state;
+
+#[path = "wakeup.rs"]
+mod wakeup;
+
+#[path = "replug.rs"]
+mod replug;
+
+#[path = "phases.rs"]
+mod phases;
+
+#[path = "lock.rs"]
+mod lock;
+
+#[path = "lock-backend.rs"]
+mod lock_backend;
+
+#[path = "auth.rs"]
+mod auth;
+
+#[path = "session.rs"]
+mod session;
+
+#[path = "store.rs"]
+mod store;
+
+#[path = "channels.rs"]
+mod channels;
+
+#[path = "history.rs"]
+mod history;
+
+#[path = "limits.rs"]
+mod limits;
+
+#[path = "budget.rs"]
+mod budget;
+
+#[path = "ext-limit.rs"]
+mod ext_limit;
+
+#[path = "profile.rs"]
+mod profile;
+
+#[path = "delay.rs"]
+mod delay;
+
+#[path = "surplus.rs"]
+mod surplus;
+
+#[path = "meter.rs"]
+mod meter;
+
+#[path = "thermal.rs"]
+mod thermal;
+
+#[path = "ocpp.rs"]
+mod ocpp;
+
 pub(crate) mod prelude {
    // pub(crate) use crate::codec::*;
     pub(crate) use crate::verbs::*;
     pub(crate) use crate::binding::*;
+    pub(crate) use crate::state::*;
+    pub(crate) use crate::wakeup::*;
+    pub(crate) use crate::replug::*;
+    pub(crate) use crate::phases::*;
+    pub(crate) use crate::lock::*;
+    pub(crate) use crate::lock_backend::*;
+    pub(crate) use crate::auth::*;
+    pub(crate) use crate::session::*;
+    pub(crate) use crate::store::*;
+    pub(crate) use crate::channels::*;
+    pub(crate) use crate::history::*;
+    pub(crate) use crate::limits::*;
+    pub(crate) use crate::budget::*;
+    pub(crate) use crate::ext_limit::*;
+    pub(crate) use crate::profile::*;
+    pub(crate) use crate::delay::*;
+    pub(crate) use crate::surplus::*;
+    pub(crate) use crate::meter::*;
+    pub(crate) use crate::thermal::*;
+    pub(crate) use crate::ocpp::*;
 }
\ No newline at end of file
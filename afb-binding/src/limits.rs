@@ -0,0 +1,68 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Current limits: client PWM requests are capped by the lowest active limit
+ *  (site budget, energy manager, schedules, pv surplus, temperature, ...) before being sent to firmware.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LimitSource {
+    Budget,
+    External,
+    Profile,
+    Surplus,
+    Thermal,
+}
+
+impl LimitSource {
+    // grid driven sources, subject to random delay
+    pub fn randomized(&self) -> bool {
+        matches!(self, LimitSource::External | LimitSource::Profile)
+    }
+}
+
+const LIMIT_SOURCES: usize = 5;
+
+#[derive(Default, Clone)]
+pub(crate) struct EvseLimits {
+    values: [Option<u32>; LIMIT_SOURCES],
+}
+
+impl EvseLimits {
+    // return true when value changed
+    pub fn set(&mut self, source: LimitSource, value: Option<u32>) -> bool {
+        let slot = &mut self.values[source as usize];
+        if *slot == value {
+            return false;
+        }
+        *slot = value;
+        true
+    }
+
+    // lowest active limit in A
+    pub fn current(&self) -> Option<u32> {
+        self.values.iter().flatten().min().copied()
+    }
+}
+
+// true when next effective limit allows more current than applied one (None: unlimited)
+pub(crate) fn limit_raised(next: Option<u32>, applied: Option<u32>) -> bool {
+    match (next, applied) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(next), Some(applied)) => next > applied,
+    }
+}
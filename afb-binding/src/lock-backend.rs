@@ -0,0 +1,303 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Lock motor backends: depending on board the motor is driven through an other
+ *  afb api (i2c binding), directly from sysfs gpio lines, by M4 firmware or not at all.
+ */
+use std::cell::Cell;
+use std::fs;
+use std::rc::Rc;
+
+use afbv4::prelude::*;
+use rpmsg::prelude::*;
+
+pub(crate) trait LockBackend {
+    fn uid(&self) -> &'static str;
+    fn actuate(&self, lock: bool) -> Result<(), AfbError>;
+
+    // backend owns a position feedback (read through position)
+    fn has_feedback(&self) -> bool {
+        false
+    }
+
+    fn position(&self) -> Result<bool, AfbError> {
+        afb_error!("lock-backend-feedback", "{} has no position feedback", self.uid())
+    }
+
+    // feedback pushed by firmware
+    fn notify(&self, _locked: bool) {}
+}
+
+#[derive(Clone)]
+pub(crate) enum LockBackendConfig {
+    None,
+    Afb {
+        api: &'static str,
+        verb: &'static str,
+        on: JsoncObj,
+        off: JsoncObj,
+    },
+    Gpio {
+        lock: &'static str,
+        unlock: &'static str,
+        pulse: u32,
+    },
+    Rpmsg {
+        feedback: bool,
+    },
+}
+
+impl LockBackendConfig {
+    pub fn from_jsonc(jconf: &JsoncObj, jlock: &JsoncObj) -> Result<Self, AfbError> {
+        let lock_api = match jlock.optional::<&'static str>("api")? {
+            Some(value) => Some(value),
+            None => jconf.optional::<&'static str>("lock_api")?,
+        };
+        let lock_verb = match jlock.optional::<&'static str>("verb")? {
+            Some(value) => Some(value),
+            None => jconf.optional::<&'static str>("lock_verb")?,
+        };
+
+        // default to afb backend when lock_api is provided (legacy config)
+        let backend = match jlock.optional::<&'static str>("backend")? {
+            Some(value) => value.to_uppercase(),
+            None if lock_api.is_some() => "AFB".to_string(),
+            None => "NONE".to_string(),
+        };
+
+        let config = match backend.as_str() {
+            "NONE" => LockBackendConfig::None,
+            "AFB" => {
+                let (api, verb) = match (lock_api, lock_verb) {
+                    (Some(api), Some(verb)) => (api, verb),
+                    _ => {
+                        return afb_error!(
+                            "lock-config-invalid",
+                            "afb lock backend requires lock_api and lock_verb"
+                        )
+                    }
+                };
+                let on = match jlock.optional::<JsoncObj>("on")? {
+                    Some(value) => value,
+                    None => {
+                        let value = JsoncObj::new();
+                        value.add("action", "on")?;
+                        value
+                    }
+                };
+                let off = match jlock.optional::<JsoncObj>("off")? {
+                    Some(value) => value,
+                    None => {
+                        let value = JsoncObj::new();
+                        value.add("action", "off")?;
+                        value
+                    }
+                };
+                LockBackendConfig::Afb { api, verb, on, off }
+            }
+            "GPIO" => {
+                let jgpio = jlock.get::<JsoncObj>("gpio")?;
+                LockBackendConfig::Gpio {
+                    lock: jgpio.get::<&'static str>("lock")?,
+                    unlock: jgpio.get::<&'static str>("unlock")?,
+                    pulse: jgpio.default::<u32>("pulse", 300)?,
+                }
+            }
+            "RPMSG" => LockBackendConfig::Rpmsg {
+                feedback: jlock.default::<bool>("feedback", false)?,
+            },
+            _ => {
+                return afb_error!(
+                    "lock-config-invalid",
+                    "lock backend should be afb|gpio|rpmsg|none"
+                )
+            }
+        };
+        Ok(config)
+    }
+
+    // api required at binding finalize time
+    pub fn api(&self) -> Option<&'static str> {
+        match self {
+            LockBackendConfig::Afb { api, .. } => Some(*api),
+            _ => None,
+        }
+    }
+
+    pub fn mk_backend(&self, apiv4: AfbApiV4, dev: Rc<TiRpmsg>) -> Box<dyn LockBackend> {
+        match self {
+            LockBackendConfig::None => Box::new(NoLock {}),
+            LockBackendConfig::Afb { api, verb, on, off } => Box::new(AfbLock {
+                apiv4,
+                api: *api,
+                verb: *verb,
+                on: on.clone(),
+                off: off.clone(),
+            }),
+            LockBackendConfig::Gpio {
+                lock,
+                unlock,
+                pulse,
+            } => Box::new(GpioLock {
+                lock: *lock,
+                unlock: *unlock,
+                pulse: *pulse,
+                gen: Rc::new(Cell::new(0)),
+            }),
+            LockBackendConfig::Rpmsg { feedback } => Box::new(RpmsgLock {
+                dev,
+                feedback: *feedback,
+                position: Cell::new(None),
+            }),
+        }
+    }
+}
+
+// tethered cable or board without lock motor
+pub(crate) struct NoLock {}
+
+impl LockBackend for NoLock {
+    fn uid(&self) -> &'static str {
+        "none"
+    }
+
+    fn actuate(&self, _lock: bool) -> Result<(), AfbError> {
+        Ok(())
+    }
+}
+
+// motor driven through an other afb api (e.g. i2c binding)
+pub(crate) struct AfbLock {
+    apiv4: AfbApiV4,
+    api: &'static str,
+    verb: &'static str,
+    on: JsoncObj,
+    off: JsoncObj,
+}
+
+impl LockBackend for AfbLock {
+    fn uid(&self) -> &'static str {
+        "afb"
+    }
+
+    fn actuate(&self, lock: bool) -> Result<(), AfbError> {
+        let payload = if lock {
+            self.on.clone()
+        } else {
+            self.off.clone()
+        };
+        AfbSubCall::call_sync(self.apiv4, self.api, self.verb, payload)?;
+        Ok(())
+    }
+}
+
+// motor driven from sysfs gpio lines (one per direction)
+pub(crate) struct GpioLock {
+    lock: &'static str,
+    unlock: &'static str,
+    pulse: u32,
+    // invalidates pending pulse release when a new command starts
+    gen: Rc<Cell<u32>>,
+}
+
+fn gpio_write(path: &str, value: &str) -> Result<(), AfbError> {
+    if let Err(error) = fs::write(path, value) {
+        return afb_error!("lock-gpio-write", "{} write fail:{}", path, error);
+    }
+    Ok(())
+}
+
+struct GpioPulseCtx {
+    path: &'static str,
+    gen: Rc<Cell<u32>>,
+    expected: u32,
+}
+
+fn gpio_pulse_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<GpioPulseCtx>()?;
+    // newer command already released this line
+    if ctx.gen.get() != ctx.expected {
+        return Ok(());
+    }
+    gpio_write(ctx.path, "0")
+}
+
+impl LockBackend for GpioLock {
+    fn uid(&self) -> &'static str {
+        "gpio"
+    }
+
+    fn actuate(&self, lock: bool) -> Result<(), AfbError> {
+        let (path, opposite) = if lock {
+            (self.lock, self.unlock)
+        } else {
+            (self.unlock, self.lock)
+        };
+        let expected = self.gen.get() + 1;
+        self.gen.set(expected);
+
+        // never drive both H-bridge inputs high (shoot-through)
+        gpio_write(opposite, "0")?;
+        gpio_write(path, "1")?;
+
+        // release motor line after pulse
+        AfbTimer::new("lock-gpio-pulse")
+            .set_period(self.pulse)
+            .set_decount(1)
+            .set_callback(gpio_pulse_cb)
+            .set_context(GpioPulseCtx {
+                path,
+                gen: self.gen.clone(),
+                expected,
+            })
+            .start()?;
+        Ok(())
+    }
+}
+
+// motor driven by M4 firmware
+pub(crate) struct RpmsgLock {
+    dev: Rc<TiRpmsg>,
+    feedback: bool,
+    position: Cell<Option<bool>>,
+}
+
+impl LockBackend for RpmsgLock {
+    fn uid(&self) -> &'static str {
+        "rpmsg"
+    }
+
+    fn actuate(&self, lock: bool) -> Result<(), AfbError> {
+        // feedback from previous command is stale
+        self.position.set(None);
+        self.dev.write(&mk_lock(lock)?)
+    }
+
+    fn has_feedback(&self) -> bool {
+        self.feedback
+    }
+
+    fn position(&self) -> Result<bool, AfbError> {
+        match self.position.get() {
+            Some(value) => Ok(value),
+            None => afb_error!("lock-rpmsg-feedback", "firmware did not report lock position"),
+        }
+    }
+
+    fn notify(&self, locked: bool) {
+        self.position.set(Some(locked));
+    }
+}
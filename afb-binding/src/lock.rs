@@ -0,0 +1,490 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Cable lock policy: when to lock/unlock the socket motor is defined from config
+ *  "lock": {"lock_on":["plug","power"], "unlock_on":["stop","relay-off","unplug"]}
+ *
+ * Motor itself is driven by a LockBackend (afb|gpio|rpmsg|none) selected from config.
+ * When a "check" feedback is configured, motor position is verified after each command,
+ *  command is retried with backoff and AllowPowerOn is withheld until lock is confirmed.
+ */
+use std::cell::Cell;
+use std::fs;
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use rpmsg::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LockTrigger {
+    Plug,
+    Auth,
+    Power,
+    Stop,
+    RelayOff,
+    Unplug,
+    SessionEnd,
+    Fault,
+}
+
+impl LockTrigger {
+    pub fn parse(value: &str) -> Result<Self, AfbError> {
+        let trigger = match value.to_uppercase().as_str() {
+            "PLUG" => LockTrigger::Plug,
+            "AUTH" => LockTrigger::Auth,
+            "POWER" => LockTrigger::Power,
+            "STOP" => LockTrigger::Stop,
+            "RELAY-OFF" => LockTrigger::RelayOff,
+            "UNPLUG" => LockTrigger::Unplug,
+            "SESSION-END" => LockTrigger::SessionEnd,
+            "FAULT" => LockTrigger::Fault,
+            _ => {
+                return afb_error!(
+                    "lock-config-invalid",
+                    "unknown lock trigger:{} (plug|auth|power|stop|relay-off|unplug|session-end|fault)",
+                    value
+                )
+            }
+        };
+        Ok(trigger)
+    }
+
+    fn parse_list(jlist: &JsoncObj) -> Result<Vec<Self>, AfbError> {
+        let mut triggers = Vec::new();
+        for idx in 0..jlist.count()? {
+            triggers.push(LockTrigger::parse(jlist.index::<&str>(idx)?)?);
+        }
+        Ok(triggers)
+    }
+}
+
+// lock motor position feedback
+#[derive(Clone)]
+pub(crate) enum LockCheck {
+    None,
+    // read verb on lock api, reply {key: value}
+    Api {
+        api: &'static str,
+        verb: &'static str,
+        query: JsoncObj,
+        key: &'static str,
+        mask: u32,
+        locked: u32,
+    },
+    // feedback line exported through sysfs
+    Gpio {
+        path: &'static str,
+        locked: u32,
+    },
+}
+
+impl LockCheck {
+    fn from_jsonc(jlock: &JsoncObj, backend: &LockBackendConfig) -> Result<Self, AfbError> {
+        let jcheck = match jlock.optional::<JsoncObj>("check")? {
+            Some(value) => value,
+            None => return Ok(LockCheck::None),
+        };
+
+        if let Some(path) = jcheck.optional::<&'static str>("gpio")? {
+            return Ok(LockCheck::Gpio {
+                path,
+                locked: jcheck.default::<u32>("locked", 1)?,
+            });
+        }
+
+        let query = match jcheck.optional::<JsoncObj>("query")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let api = match jcheck.optional::<&'static str>("api")? {
+            Some(value) => value,
+            None => match backend.api() {
+                Some(value) => value,
+                None => return afb_error!("lock-config-invalid", "lock check requires an api"),
+            },
+        };
+
+        Ok(LockCheck::Api {
+            api,
+            verb: jcheck.get::<&'static str>("verb")?,
+            query,
+            key: jcheck.default::<&'static str>("key", "value")?,
+            mask: jcheck.default::<u32>("mask", u32::MAX)?,
+            locked: jcheck.get::<u32>("locked")?,
+        })
+    }
+}
+
+// backoff doubles at each retry
+const LOCK_RETRY_MAX: u32 = 16;
+
+#[derive(Clone)]
+pub(crate) struct LockPolicy {
+    pub lock_on: Vec<LockTrigger>,
+    pub unlock_on: Vec<LockTrigger>,
+    // tethered cable: no lock motor
+    pub tethered: bool,
+    pub backend: LockBackendConfig,
+    pub check: LockCheck,
+    // number of command retries before raising lock-fault
+    pub retry: u32,
+    // first retry delay in ms, doubled at each retry
+    pub backoff: u32,
+    // delay between command and first position check
+    pub settle: u32,
+}
+
+impl LockPolicy {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jlock = match jconf.optional::<JsoncObj>("lock")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let lock_on = match jlock.optional::<JsoncObj>("lock_on")? {
+            Some(jlist) => LockTrigger::parse_list(&jlist)?,
+            None => vec![LockTrigger::Plug, LockTrigger::Power],
+        };
+
+        let unlock_on = match jlock.optional::<JsoncObj>("unlock_on")? {
+            Some(jlist) => LockTrigger::parse_list(&jlist)?,
+            None => vec![LockTrigger::Stop, LockTrigger::RelayOff, LockTrigger::Unplug],
+        };
+
+        let tethered = jlock.default::<bool>("tethered", false)?;
+        let backend = if tethered {
+            LockBackendConfig::None
+        } else {
+            LockBackendConfig::from_jsonc(jconf, &jlock)?
+        };
+
+        let retry = jlock.default::<u32>("retry", 3)?;
+        if retry > LOCK_RETRY_MAX {
+            return afb_error!(
+                "lock-config-invalid",
+                "lock retry:{} should be <= {}",
+                retry,
+                LOCK_RETRY_MAX
+            );
+        }
+
+        Ok(LockPolicy {
+            lock_on,
+            unlock_on,
+            tethered,
+            check: LockCheck::from_jsonc(&jlock, &backend)?,
+            backend,
+            retry,
+            backoff: jlock.default::<u32>("backoff", 500)?,
+            settle: jlock.default::<u32>("settle", 500)?,
+        })
+    }
+}
+
+pub(crate) struct LockCtx {
+    pub apiv4: AfbApiV4,
+    pub backend: Box<dyn LockBackend>,
+    pub policy: LockPolicy,
+    pub status: Cell<LockState>,
+    // last command sent to motor
+    target: Cell<Option<bool>>,
+    attempt: Cell<u32>,
+    gen: Cell<u32>,
+}
+
+impl LockCtx {
+    pub fn new(apiv4: AfbApiV4, dev: Rc<TiRpmsg>, policy: LockPolicy) -> Self {
+        LockCtx {
+            apiv4,
+            backend: policy.backend.mk_backend(apiv4, dev),
+            policy,
+            status: Cell::new(LockState::Unknown),
+            target: Cell::new(None),
+            attempt: Cell::new(0),
+            gen: Cell::new(0),
+        }
+    }
+
+    fn send(&self, lock: bool) -> Result<(), AfbError> {
+        afb_log_msg!(Debug, None, "lock-{} actuate lock:{}", self.backend.uid(), lock);
+        self.backend.actuate(lock)
+    }
+
+    // return true when motor feedback reports locked
+    fn read(&self) -> Result<bool, AfbError> {
+        match &self.policy.check {
+            LockCheck::None => self.backend.position(),
+            LockCheck::Api {
+                api,
+                verb,
+                query,
+                key,
+                mask,
+                locked,
+            } => {
+                let reply = AfbSubCall::call_sync(self.apiv4, *api, *verb, query.clone())?;
+                let value = reply.get::<JsoncObj>(0)?.get::<u32>(*key)?;
+                Ok(value & *mask == *locked)
+            }
+            LockCheck::Gpio { path, locked } => match fs::read_to_string(path) {
+                Ok(value) => match value.trim().parse::<u32>() {
+                    Ok(value) => Ok(value == *locked),
+                    Err(error) => afb_error!("lock-check-gpio", "{} invalid value:{}", path, error),
+                },
+                Err(error) => afb_error!("lock-check-gpio", "{} read fail:{}", path, error),
+            },
+        }
+    }
+
+    pub fn checked(&self) -> bool {
+        !self.policy.tethered
+            && (!matches!(self.policy.check, LockCheck::None) || self.backend.has_feedback())
+    }
+
+    // api required at binding finalize time
+    pub fn apis(policy: &LockPolicy) -> Vec<&'static str> {
+        let mut apis = Vec::new();
+        if let Some(api) = policy.backend.api() {
+            apis.push(api);
+        }
+        if let LockCheck::Api { api, .. } = policy.check {
+            if !apis.contains(&api) {
+                apis.push(api);
+            }
+        }
+        apis
+    }
+
+    // AllowPowerOn is withheld until lock is confirmed
+    pub fn confirmed(&self) -> bool {
+        !self.checked()
+            || self.policy.lock_on.is_empty()
+            || self.status.get() == LockState::Locked
+    }
+}
+
+struct LockTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+fn lock_schedule(state: &Rc<EvseState>, delay: u32) -> Result<(), AfbError> {
+    AfbTimer::new("lock-check")
+        .set_period(delay)
+        .set_decount(1)
+        .set_callback(lock_check_cb)
+        .set_context(LockTimerCtx {
+            state: state.clone(),
+            gen: state.lock.gen.get(),
+        })
+        .start()?;
+    Ok(())
+}
+
+fn lock_check_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<LockTimerCtx>()?;
+    let state = &ctx.state;
+    let lock = &state.lock;
+    if ctx.gen != lock.gen.get() {
+        return Ok(());
+    }
+    let target = match lock.target.get() {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+
+    match lock.read() {
+        Ok(value) if value == target => return lock_done(state, target),
+        Ok(_) => {}
+        Err(error) => afb_log_msg!(Warning, None, "{}: lock check fail:{}", state.uid, error),
+    }
+
+    let attempt = lock.attempt.get() + 1;
+    if attempt > lock.policy.retry {
+        afb_log_msg!(
+            Critical,
+            None,
+            "{}: lock motor fail to {} after {} retries",
+            state.uid,
+            if target { "lock" } else { "unlock" },
+            lock.policy.retry
+        );
+        lock.status.set(LockState::Fault);
+        // next lock/unlock request starts a fresh attempt
+        lock.target.set(None);
+        state.push(Iec6185Msg::LockFault(true));
+        store_fault(state, "lock-fault");
+        return lock_release_unplug(state);
+    }
+
+    lock.attempt.set(attempt);
+    // failed command is caught by next position check
+    if let Err(error) = lock.send(target) {
+        afb_log_msg!(Warning, None, "{}: lock command fail:{}", state.uid, error);
+    }
+    lock_schedule(state, lock.policy.backoff.saturating_mul(1 << (attempt - 1)))
+}
+
+// motor position confirmed
+fn lock_done(state: &Rc<EvseState>, lock: bool) -> Result<(), AfbError> {
+    state.lock.status.set(if lock {
+        LockState::Locked
+    } else {
+        LockState::Unlocked
+    });
+
+    if state.lock.checked() {
+        state.push(Iec6185Msg::Locked(lock));
+    }
+
+    if lock {
+        state.apply_power()
+    } else {
+        lock_release_unplug(state)
+    }
+}
+
+// unplug notification waits for unlock confirmation
+fn lock_release_unplug(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let pending = {
+        let mut data = state.data.borrow_mut();
+        let pending = data.unplug_pending;
+        data.unplug_pending = false;
+        pending
+    };
+    if pending {
+        state.push(Iec6185Msg::Plugged(false));
+        session_end(state);
+    }
+    Ok(())
+}
+
+pub(crate) fn lock_set(state: &Rc<EvseState>, lock: bool) -> Result<(), AfbError> {
+    let ctx = &state.lock;
+    if ctx.policy.tethered || ctx.target.get() == Some(lock) {
+        return Ok(());
+    }
+    ctx.target.set(Some(lock));
+    ctx.attempt.set(0);
+    ctx.gen.set(ctx.gen.get() + 1);
+    ctx.status.set(if lock {
+        LockState::Locking
+    } else {
+        LockState::Unlocking
+    });
+
+    // never leave power allowed on an unlocked connector
+    if !lock && ctx.checked() {
+        state.dev.write(&mk_power(false)?)?;
+    }
+
+    if !ctx.checked() {
+        if let Err(error) = ctx.send(lock) {
+            ctx.target.set(None);
+            return Err(error);
+        }
+        return lock_done(state, lock);
+    }
+    // checked motor: failed command counts as a failed attempt
+    if let Err(error) = ctx.send(lock) {
+        afb_log_msg!(Warning, None, "{}: lock command fail:{}", state.uid, error);
+    }
+    lock_schedule(state, ctx.policy.settle)
+}
+
+// apply lock policy for a given connector event
+pub(crate) fn lock_trigger(state: &Rc<EvseState>, event: LockTrigger) -> Result<(), AfbError> {
+    if state.lock.policy.lock_on.contains(&event) {
+        lock_set(state, true)
+    } else if state.lock.policy.unlock_on.contains(&event) {
+        lock_set(state, false)
+    } else {
+        Ok(())
+    }
+}
+
+// true when Plugged(false) should wait for unlock confirmation
+pub(crate) fn lock_unplug_pending(state: &Rc<EvseState>) -> bool {
+    state.lock.checked() && state.lock.status.get() == LockState::Unlocking
+}
+
+// unplug, fault latch and shutdown always release the cable once relay is open
+pub(crate) fn lock_auto_unlock(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let relay_on = {
+        let mut data = state.data.borrow_mut();
+        data.unlock_pending = data.relay_on;
+        data.relay_on
+    };
+    if relay_on {
+        afb_log_msg!(Notice, None, "{}: unlock deferred until relay open", state.uid);
+        return Ok(());
+    }
+    lock_set(state, false)
+}
+
+// called on POWER_OFF
+pub(crate) fn lock_relay_open(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let pending = {
+        let mut data = state.data.borrow_mut();
+        let pending = data.unlock_pending;
+        data.unlock_pending = false;
+        pending
+    };
+    if pending {
+        lock_set(state, false)?;
+    }
+    Ok(())
+}
+
+// operator unlock: refused while relay is closed or PWM active unless forced
+pub(crate) fn lock_release(state: &Rc<EvseState>, force: bool) -> Result<(), AfbError> {
+    let (relay_on, pwm_active) = {
+        let data = state.data.borrow();
+        (
+            data.relay_on,
+            data.pwm_state != PwmState::Off || data.pause.is_some() || data.sequence.is_some(),
+        )
+    };
+
+    if !force && (relay_on || pwm_active) {
+        return afb_error!(
+            "unlock-refused",
+            "relay:{} pwm-active:{} (power-off and pwm-off required)",
+            if relay_on { "closed" } else { "open" },
+            pwm_active
+        );
+    }
+
+    // operator may need to resend a command whatever the last known target
+    state.lock.target.set(None);
+    lock_set(state, false)
+}
+
+// binder shutdown: cut power then release cable (no verification possible)
+pub(crate) fn lock_shutdown(state: &Rc<EvseState>) {
+    if state.lock.policy.tethered {
+        return;
+    }
+    for msg in [mk_power(false), mk_pwm(&PwmState::Off, 0.0)].into_iter().flatten() {
+        let _ = state.dev.write(&msg);
+    }
+    if let Err(error) = state.lock.backend.actuate(false) {
+        afb_log_msg!(Critical, None, "{}: shutdown unlock fail:{}", state.uid, error);
+    }
+}
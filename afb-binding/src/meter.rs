@@ -0,0 +1,209 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Connector meter events {"current":[A..], "voltage":[V..], "energy":Wh} feed session
+ *  energy (energy register delta, or integrated power when meter has no register) and
+ *  supervise the EV: current above PWM allowance + IEC61851 tolerance (+2A up to 20A,
+ *  +10% above) for 'delay' ms opens the relay, current with relay open raises a fault.
+ */
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+pub(crate) const OVERCURRENT_FAULT: &str = "overcurrent";
+pub(crate) const OPEN_CURRENT_FAULT: &str = "current-relay-open";
+
+#[derive(Clone, Copy)]
+pub(crate) struct MeterConfig {
+    pub api: &'static str,
+    pub event: &'static str,
+    pub subscribe: &'static str,
+    // voltage used when meter does not report it (V)
+    pub voltage: f64,
+    // supervision delay in ms
+    pub delay: u32,
+    // max current with relay open (A)
+    pub open_current: f64,
+}
+
+impl MeterConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Option<Self>, AfbError> {
+        let jmeter = match jconf.optional::<JsoncObj>("meter")? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        Ok(Some(MeterConfig {
+            api: jmeter.get::<&'static str>("api")?,
+            event: jmeter.get::<&'static str>("event")?,
+            subscribe: jmeter.default::<&'static str>("subscribe", "subscribe")?,
+            voltage: jmeter.default::<f64>("voltage", 230.0)?,
+            delay: jmeter.default::<u32>("delay", 5000)?,
+            open_current: jmeter.default::<f64>("open_current", 1.0)?,
+        }))
+    }
+}
+
+// IEC61851-1 tolerance on EV current vs PWM allowance
+fn allowance(offered: u32) -> f64 {
+    let offered = offered as f64;
+    if offered <= 20.0 {
+        offered + 2.0
+    } else {
+        offered * 1.1
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct MeterReading {
+    pub current: Vec<f64>,
+    pub voltage: Vec<f64>,
+    pub energy: Option<f64>,
+    pub at: Instant,
+}
+
+pub(crate) struct MeterCtx {
+    pub config: MeterConfig,
+    pub last: RefCell<Option<MeterReading>>,
+    over_since: Cell<Option<Instant>>,
+    open_since: Cell<Option<Instant>>,
+}
+
+impl MeterCtx {
+    pub fn new(config: MeterConfig) -> Self {
+        MeterCtx {
+            config,
+            last: RefCell::new(None),
+            over_since: Cell::new(None),
+            open_since: Cell::new(None),
+        }
+    }
+
+    // true once condition held for supervision delay
+    fn persist(&self, since: &Cell<Option<Instant>>, active: bool) -> bool {
+        if !active {
+            since.set(None);
+            return false;
+        }
+        let start = match since.get() {
+            Some(value) => value,
+            None => {
+                let now = Instant::now();
+                since.set(Some(now));
+                now
+            }
+        };
+        start.elapsed() >= Duration::from_millis(self.config.delay as u64)
+    }
+}
+
+// energy since previous reading (Wh)
+fn meter_energy(config: &MeterConfig, previous: &MeterReading, reading: &MeterReading) -> f64 {
+    if let (Some(before), Some(now)) = (previous.energy, reading.energy) {
+        return (now - before).max(0.0);
+    }
+    let power: f64 = reading
+        .current
+        .iter()
+        .enumerate()
+        .map(|(idx, amps)| amps * reading.voltage.get(idx).copied().unwrap_or(config.voltage))
+        .sum();
+    power * reading.at.duration_since(previous.at).as_secs_f64() / 3600.0
+}
+
+// latch fault and report it once
+fn meter_fault(state: &EvseState, fault: &str) {
+    if state.data.borrow().faults.iter().any(|value| value == fault) {
+        return;
+    }
+    afb_log_msg!(Critical, None, "{}: meter fault:{}", state.uid, fault);
+    state.fault_set(fault);
+    session_fault(state, fault);
+    store_fault(state, fault);
+    state.push(Iec6185Msg::Error(fault.to_string()));
+}
+
+pub(crate) fn meter_update(state: &Rc<EvseState>, reading: MeterReading) -> Result<(), AfbError> {
+    let meter = match &state.meter {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    let (relay_on, offered) = {
+        let data = state.data.borrow();
+        (data.relay_on, data.offered)
+    };
+
+    if let Some(previous) = meter.last.replace(Some(reading.clone())) {
+        session_energy(state, meter_energy(&meter.config, &previous, &reading));
+    }
+
+    let peak = reading.current.iter().copied().fold(0.0, f64::max);
+    let over = relay_on && peak > allowance(offered.unwrap_or(0));
+    if meter.persist(&meter.over_since, over) {
+        meter.over_since.set(None);
+        afb_log_msg!(
+            Warning,
+            None,
+            "{}: EV draws {:.1}A over allowance {:?}A, opening relay",
+            state.uid,
+            peak,
+            offered
+        );
+        meter_fault(state, OVERCURRENT_FAULT);
+        state.withdraw_power()?;
+    }
+
+    let leak = !relay_on && peak > meter.config.open_current;
+    if meter.persist(&meter.open_since, leak) {
+        meter_fault(state, OPEN_CURRENT_FAULT);
+    }
+    Ok(())
+}
+
+pub(crate) struct MeterEvtCtx {
+    pub state: Rc<EvseState>,
+}
+
+fn meter_values(jevent: &JsoncObj, key: &str) -> Result<Vec<f64>, AfbError> {
+    let mut values = Vec::new();
+    if let Some(jvalues) = jevent.optional::<JsoncObj>(key)? {
+        for idx in 0..jvalues.count()? {
+            values.push(jvalues.index::<f64>(idx)?);
+        }
+    }
+    Ok(values)
+}
+
+// meter event {"current":[A..],"voltage":[V..],"energy":Wh}
+pub(crate) fn meter_event_cb(
+    _evt: &AfbEventMsg,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<MeterEvtCtx>()?;
+    let jevent = args.get::<JsoncObj>(0)?;
+    let reading = MeterReading {
+        current: meter_values(&jevent, "current")?,
+        voltage: meter_values(&jevent, "voltage")?,
+        energy: jevent.optional::<f64>("energy")?,
+        at: Instant::now(),
+    };
+    meter_update(&ctx.state, reading)
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * OCPP connector status derived from binding state, re-evaluated after every
+ *  published event and sent on 'ocpp' channel when it changes. Binding pauses
+ *  (budget, surplus, operator, ...) and withheld power report SuspendedEVSE.
+ */
+use crate::prelude::*;
+use typesv4::prelude::*;
+
+fn ocpp_error(fault: &str) -> OcppErrorCode {
+    match fault {
+        OVERCURRENT_FAULT | "ERROR_OVER_CURRENT" => OcppErrorCode::OverCurrentFailure,
+        OPEN_CURRENT_FAULT | "ERROR_RELAIS" => OcppErrorCode::PowerSwitchFailure,
+        "ERROR_RCD" => OcppErrorCode::GroundFailure,
+        "ERROR_E" | "ERROR_DF" => OcppErrorCode::EVCommunicationError,
+        "PERMANENT_FAULT" => OcppErrorCode::InternalError,
+        _ => OcppErrorCode::OtherError,
+    }
+}
+
+pub(crate) fn ocpp_status(state: &EvseState) -> OcppConnector {
+    let data = state.data.borrow();
+    let lock_fault = state.lock.status.get() == LockState::Fault;
+    let overtemp = match &state.thermal {
+        Some(thermal) => thermal.derating(),
+        None => false,
+    };
+
+    let (error_code, vendor_error) = match data.faults.first() {
+        Some(fault) => (ocpp_error(fault), Some(fault.clone())),
+        None if lock_fault => (
+            OcppErrorCode::ConnectorLockFailure,
+            Some("lock-fault".to_string()),
+        ),
+        None if overtemp => (OcppErrorCode::HighTemperature, None),
+        None => (OcppErrorCode::NoError, None),
+    };
+
+    let deauthorized = match &data.session.current {
+        Some(session) => session.stop_reason == Some(StopReason::Deauthorized),
+        None => false,
+    };
+    let status = if !data.faults.is_empty() || lock_fault {
+        OcppStatus::Faulted
+    } else if !data.plugged {
+        match data.pause {
+            Some(PauseReason::Operator) => OcppStatus::Unavailable,
+            _ => OcppStatus::Available,
+        }
+    } else if deauthorized && !data.authorized {
+        // transaction stopped, cable still plugged
+        OcppStatus::Finishing
+    } else if data.pause.is_some() || data.offered == Some(0) {
+        OcppStatus::SuspendedEVSE
+    } else if data.relay_on {
+        OcppStatus::Charging
+    } else if !data.authorized {
+        OcppStatus::Preparing
+    } else if data.power_rqt || data.offered.is_none() {
+        // car asks for power (or no pwm offer yet), binding holds it
+        OcppStatus::SuspendedEVSE
+    } else {
+        OcppStatus::SuspendedEV
+    };
+
+    OcppConnector {
+        status,
+        connector_status: match status {
+            OcppStatus::Available => OcppConnectorStatus::Available,
+            OcppStatus::Unavailable => OcppConnectorStatus::Unavailable,
+            OcppStatus::Faulted => OcppConnectorStatus::Faulted,
+            _ => OcppConnectorStatus::Occupied,
+        },
+        error_code,
+        vendor_error,
+    }
+}
+
+// publish status when changed
+pub(crate) fn ocpp_update(state: &EvseState) {
+    let ocpp = ocpp_status(state);
+    if state.ocpp.borrow().as_ref() == Some(&ocpp) {
+        return;
+    }
+    state.ocpp.replace(Some(ocpp.clone()));
+    state.push(Iec6185Msg::Ocpp(ocpp));
+}
@@ -0,0 +1,272 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * 1p/3p switching: relays may only be switched without load. Sequence is
+ *  pause -> wait EV stop -> relay open (POWER_OFF) -> switch -> settle -> resume
+ *  A relay that never opens aborts the switch, a switch the firmware never confirms
+ *  latches a fault and keeps the connector paused on the previous phase count.
+ */
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use rpmsg::prelude::*;
+use typesv4::prelude::*;
+
+pub(crate) const PHASE_SWITCH_FAULT: &str = "phase-switch-timeout";
+
+#[derive(Clone, Copy)]
+pub(crate) struct PhasesConfig {
+    // phase count at startup
+    pub count: u32,
+    // delay between firmware switch confirmation and resume
+    pub settle: u32,
+    // max wait time for firmware confirmation
+    pub confirm: u32,
+    // max wait time for relay opening once paused
+    pub relay: u32,
+}
+
+impl PhasesConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jphases = match jconf.optional::<JsoncObj>("phases")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let count = jphases.default::<u32>("count", 3)?;
+        if count != 1 && count != 3 {
+            return afb_error!("phases-config-invalid", "phases count should be 1|3");
+        }
+
+        Ok(PhasesConfig {
+            count,
+            settle: jphases.default::<u32>("settle", 5000)?,
+            confirm: jphases.default::<u32>("confirm", 2000)?,
+            relay: jphases.default::<u32>("relay", 10000)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PhaseStep {
+    Idle,
+    WaitRelay,
+    WaitConfirm,
+    Settle,
+}
+
+struct PhasesTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+fn phases_confirm_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PhasesTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.seq_gen.get() || state.data.borrow().phase_step != PhaseStep::WaitConfirm {
+        return Ok(());
+    }
+
+    afb_log_msg!(
+        Error,
+        None,
+        "{}: firmware did not confirm phase switching",
+        state.uid
+    );
+
+    // relay state unknown: ask back for previous count and stay paused
+    let previous = {
+        let mut data = state.data.borrow_mut();
+        data.phase_target = data.phases;
+        data.phase_paused = false;
+        data.phases
+    };
+    if let Err(error) = mk_phases(previous).and_then(|msg| state.dev.write(&msg)) {
+        afb_log_msg!(Error, None, "{}: phase revert failed:{}", state.uid, error);
+    }
+    state.fault_set(PHASE_SWITCH_FAULT);
+    session_fault(state, PHASE_SWITCH_FAULT);
+    store_fault(state, PHASE_SWITCH_FAULT);
+    state.push(Iec6185Msg::Error(PHASE_SWITCH_FAULT.to_string()));
+    phases_done(state)
+}
+
+// car never released the load, switching is abandoned
+fn phases_relay_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PhasesTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.seq_gen.get() || state.data.borrow().phase_step != PhaseStep::WaitRelay {
+        return Ok(());
+    }
+
+    afb_log_msg!(
+        Warning,
+        None,
+        "{}: relay still closed, phase switching aborted",
+        state.uid
+    );
+    {
+        let mut data = state.data.borrow_mut();
+        data.phase_target = data.phases;
+    }
+    state.push(Iec6185Msg::Error("phase-switch-relay-timeout".to_string()));
+    phases_done(state)
+}
+
+fn phases_settle_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PhasesTimerCtx>()?;
+    if ctx.gen != ctx.state.seq_gen.get() {
+        return Ok(());
+    }
+    phases_done(&ctx.state)
+}
+
+// end of sequence, resume charging when we paused it
+fn phases_done(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let paused = {
+        let mut data = state.data.borrow_mut();
+        data.phase_step = PhaseStep::Idle;
+        data.sequence = None;
+        let paused = data.phase_paused;
+        data.phase_paused = false;
+        paused
+    };
+    state.seq_gen.set(state.seq_gen.get() + 1);
+
+    if paused {
+        pause_resume(state)?;
+    }
+    Ok(())
+}
+
+// relay is open, ask firmware to switch
+fn phases_switch(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let target = {
+        let mut data = state.data.borrow_mut();
+        data.phase_step = PhaseStep::WaitConfirm;
+        data.phase_target
+    };
+
+    afb_log_msg!(Notice, None, "{}: switch to {} phase(s)", state.uid, target);
+    state.dev.write(&mk_phases(target)?)?;
+
+    AfbTimer::new("phases-confirm")
+        .set_period(state.phases.confirm)
+        .set_decount(1)
+        .set_callback(phases_confirm_cb)
+        .set_context(PhasesTimerCtx {
+            state: state.clone(),
+            gen: state.seq_gen.get(),
+        })
+        .start()?;
+    Ok(())
+}
+
+pub(crate) fn phases_start(state: &Rc<EvseState>, count: u32) -> Result<(), AfbError> {
+    if count != 1 && count != 3 {
+        return afb_error!("phases-invalid-query", "phase count should be 1|3");
+    }
+
+    let paused = {
+        let mut data = state.data.borrow_mut();
+        if let Some(sequence) = data.sequence {
+            return afb_error!("phases-busy", "sequence:{:?} already running", sequence);
+        }
+        // sequence pauses a running session, nothing to switch without a car
+        if !data.plugged {
+            return afb_error!("phases-no-car", "phase switching requires a plugged car");
+        }
+        if data.phases == count {
+            return Ok(());
+        }
+        data.sequence = Some(EvseSequence::PhaseSwitch);
+        data.phase_target = count;
+        data.phase_step = PhaseStep::WaitRelay;
+        data.phase_paused = data.pause.is_none();
+        data.pause.is_some()
+    };
+    state.seq_gen.set(state.seq_gen.get() + 1);
+
+    if !paused {
+        pause_start(state, PauseReason::PhaseSwitch)?;
+    }
+
+    // pause only waits for EV stop, relay opening is confirmed by POWER_OFF
+    if state.data.borrow().relay_on {
+        AfbTimer::new("phases-relay")
+            .set_period(state.phases.relay)
+            .set_decount(1)
+            .set_callback(phases_relay_cb)
+            .set_context(PhasesTimerCtx {
+                state: state.clone(),
+                gen: state.seq_gen.get(),
+            })
+            .start()?;
+        return Ok(());
+    }
+    phases_switch(state)
+}
+
+// called on POWER_OFF
+pub(crate) fn phases_relay_open(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    if state.data.borrow().phase_step != PhaseStep::WaitRelay {
+        return Ok(());
+    }
+    phases_switch(state)
+}
+
+// called when firmware confirms the active phase count
+pub(crate) fn phases_confirm(state: &Rc<EvseState>, count: u32) -> Result<(), AfbError> {
+    let waiting = {
+        let mut data = state.data.borrow_mut();
+        data.phases = count;
+        if data.phase_step == PhaseStep::WaitConfirm {
+            data.phase_step = PhaseStep::Settle;
+            true
+        } else {
+            false
+        }
+    };
+    state.push(Iec6185Msg::Phases(count));
+
+    if waiting {
+        AfbTimer::new("phases-settle")
+            .set_period(state.phases.settle)
+            .set_decount(1)
+            .set_callback(phases_settle_cb)
+            .set_context(PhasesTimerCtx {
+                state: state.clone(),
+                gen: state.seq_gen.get(),
+            })
+            .start()?;
+    }
+    Ok(())
+}
+
+// car left during the sequence, drop our own pause
+pub(crate) fn phases_abort(state: &Rc<EvseState>) {
+    let mut data = state.data.borrow_mut();
+    if data.phase_step == PhaseStep::Idle {
+        return;
+    }
+    data.phase_step = PhaseStep::Idle;
+    if data.phase_paused {
+        data.phase_paused = false;
+        data.pause = None;
+        data.pause_pending = false;
+    }
+}
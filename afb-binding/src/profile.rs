@@ -0,0 +1,357 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * OCPP SmartCharging like schedules evaluated locally (works while backend is offline).
+ *  absolute: periods start from 'start' (utc epoch s)
+ *  daily/weekly: periods start from local midnight/monday 00:00 (utc + utc_offset)
+ *  At a given time the highest stack level profile with an active period wins.
+ */
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use serde::Serialize;
+
+const DAY: u64 = 86400;
+const WEEK: u64 = 7 * DAY;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ProfileKind {
+    Absolute,
+    Daily,
+    Weekly,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub(crate) struct ProfilePeriod {
+    // offset in seconds from profile start
+    pub start: u64,
+    pub limit: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phases: Option<u32>,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct ChargingProfile {
+    pub id: u32,
+    pub stack: u32,
+    pub kind: ProfileKind,
+    pub start: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid_to: Option<u64>,
+    pub periods: Vec<ProfilePeriod>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+pub(crate) struct ScheduleSlot {
+    pub start: u64,
+    pub limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phases: Option<u32>,
+}
+
+impl ChargingProfile {
+    pub fn from_jsonc(jprofile: &JsoncObj) -> Result<Self, AfbError> {
+        let kind = match jprofile
+            .default::<&'static str>("kind", "absolute")?
+            .to_uppercase()
+            .as_str()
+        {
+            "ABSOLUTE" => ProfileKind::Absolute,
+            "DAILY" => ProfileKind::Daily,
+            "WEEKLY" => ProfileKind::Weekly,
+            _ => {
+                return afb_error!(
+                    "profile-invalid",
+                    "profile kind should be absolute|daily|weekly"
+                )
+            }
+        };
+
+        let jperiods = jprofile.get::<JsoncObj>("periods")?;
+        let mut periods = Vec::new();
+        for idx in 0..jperiods.count()? {
+            let jperiod = jperiods.index::<JsoncObj>(idx)?;
+            let phases = jperiod.optional::<u32>("phases")?;
+            if let Some(count) = phases {
+                if count != 1 && count != 3 {
+                    return afb_error!("profile-invalid", "period phases should be 1|3");
+                }
+            }
+            let limit = jperiod.get::<u32>("limit")?;
+            if limit < IEC_MIN_CURRENT {
+                return afb_error!(
+                    "profile-invalid",
+                    "period limit:{} should be >= {}A",
+                    limit,
+                    IEC_MIN_CURRENT
+                );
+            }
+            periods.push(ProfilePeriod {
+                start: jperiod.default::<u64>("start", 0)?,
+                limit,
+                phases,
+            });
+        }
+        if periods.is_empty() {
+            return afb_error!("profile-invalid", "profile requires at least one period");
+        }
+        periods.sort_by_key(|period| period.start);
+
+        let profile = ChargingProfile {
+            id: jprofile.get::<u32>("id")?,
+            stack: jprofile.default::<u32>("stack", 0)?,
+            kind,
+            start: match kind {
+                ProfileKind::Absolute => jprofile.get::<u64>("start")?,
+                _ => 0,
+            },
+            duration: jprofile.optional::<u64>("duration")?,
+            valid_from: jprofile.optional::<u64>("valid_from")?,
+            valid_to: jprofile.optional::<u64>("valid_to")?,
+            periods,
+        };
+
+        if let (Some(cycle), Some(last)) = (profile.cycle(), profile.periods.last()) {
+            if last.start >= cycle {
+                return afb_error!("profile-invalid", "period start:{} over cycle", last.start);
+            }
+        }
+        Ok(profile)
+    }
+
+    fn cycle(&self) -> Option<u64> {
+        match self.kind {
+            ProfileKind::Absolute => None,
+            ProfileKind::Daily => Some(DAY),
+            ProfileKind::Weekly => Some(WEEK),
+        }
+    }
+
+    // start of current cycle (utc epoch s), recurring cycles follow local time
+    fn origin(&self, utc: u64, offset: i64) -> Option<u64> {
+        let local = (utc as i64 + offset).max(0) as u64;
+        let origin = match self.kind {
+            ProfileKind::Absolute => return Some(self.start).filter(|start| *start <= utc),
+            ProfileKind::Daily => local - local % DAY,
+            // epoch day 0 was a thursday
+            ProfileKind::Weekly => local - (local + 3 * DAY) % WEEK,
+        };
+        Some((origin as i64 - offset).max(0) as u64)
+    }
+
+    fn active(&self, utc: u64, offset: i64) -> Option<&ProfilePeriod> {
+        if self.valid_from.is_some_and(|from| utc < from)
+            || self.valid_to.is_some_and(|to| utc >= to)
+        {
+            return None;
+        }
+        let elapsed = utc - self.origin(utc, offset)?;
+        if self.duration.is_some_and(|duration| elapsed >= duration) {
+            return None;
+        }
+        self.periods.iter().rev().find(|period| period.start <= elapsed)
+    }
+
+    // every time the profile may change within [from, to[ (utc epoch s)
+    fn boundaries(&self, from: u64, to: u64, offset: i64, times: &mut Vec<u64>) {
+        times.extend(self.valid_from);
+        times.extend(self.valid_to);
+
+        let mut origin = match self.cycle() {
+            None => self.start,
+            Some(_) => match self.origin(from, offset) {
+                Some(value) => value,
+                None => return,
+            },
+        };
+        loop {
+            times.extend(self.periods.iter().map(|period| origin.saturating_add(period.start)));
+            times.extend(self.duration.map(|duration| origin.saturating_add(duration)));
+            match self.cycle() {
+                Some(cycle) if origin.saturating_add(cycle) < to => origin += cycle,
+                _ => break,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct ProfileConfig {
+    // evaluation period in ms
+    pub tic: u32,
+    // local time offset from utc in minutes
+    pub utc_offset: i32,
+}
+
+#[derive(Clone)]
+pub(crate) struct ProfileCtx {
+    pub config: ProfileConfig,
+    pub profiles: RefCell<Vec<ChargingProfile>>,
+}
+
+impl ProfileCtx {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jprofiles = match jconf.optional::<JsoncObj>("profiles")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let mut profiles = Vec::new();
+        if let Some(jlist) = jprofiles.optional::<JsoncObj>("list")? {
+            for idx in 0..jlist.count()? {
+                profiles.push(ChargingProfile::from_jsonc(&jlist.index::<JsoncObj>(idx)?)?);
+            }
+        }
+
+        Ok(ProfileCtx {
+            config: ProfileConfig {
+                tic: jprofiles.default::<u32>("tic", 30000)?,
+                utc_offset: jprofiles.default::<i32>("utc_offset", 0)?,
+            },
+            profiles: RefCell::new(profiles),
+        })
+    }
+
+    fn offset(&self) -> i64 {
+        self.config.utc_offset as i64 * 60
+    }
+
+    // composite limit at a given utc time
+    pub fn evaluate(&self, utc: u64) -> ScheduleSlot {
+        let profiles = self.profiles.borrow();
+        let winner = profiles
+            .iter()
+            .filter_map(|profile| {
+                profile
+                    .active(utc, self.offset())
+                    .map(|period| (profile.stack, period))
+            })
+            .max_by_key(|(stack, _)| *stack);
+
+        ScheduleSlot {
+            start: utc,
+            limit: winner.map(|(_, period)| period.limit),
+            phases: winner.and_then(|(_, period)| period.phases),
+        }
+    }
+
+    // composite schedule for [from, from+duration[ (utc epoch s), duration capped to a week
+    pub fn composite(&self, from: u64, duration: u64) -> Vec<ScheduleSlot> {
+        let to = from.saturating_add(duration.min(WEEK));
+        let mut times = vec![from];
+        for profile in self.profiles.borrow().iter() {
+            profile.boundaries(from, to, self.offset(), &mut times);
+        }
+        times.retain(|time| *time >= from && *time < to);
+        times.sort_unstable();
+        times.dedup();
+
+        let mut slots: Vec<ScheduleSlot> = Vec::new();
+        for time in times {
+            let slot = self.evaluate(time);
+            match slots.last() {
+                Some(last) if last.limit == slot.limit && last.phases == slot.phases => {}
+                _ => slots.push(slot),
+            }
+        }
+        slots
+    }
+
+    // profiles and composite schedule as a json string
+    pub fn to_json(&self, from: u64, duration: u64) -> Result<String, AfbError> {
+        #[derive(Serialize)]
+        struct ProfileReply<'a> {
+            profiles: &'a [ChargingProfile],
+            schedule: Vec<ScheduleSlot>,
+        }
+        let reply = ProfileReply {
+            profiles: &self.profiles.borrow(),
+            schedule: self.composite(from, duration),
+        };
+        match serde_json::to_string(&reply) {
+            Ok(value) => Ok(value),
+            Err(error) => afb_error!("profile-encode-fail", "{}", error),
+        }
+    }
+
+    pub fn set(&self, profile: ChargingProfile) {
+        let mut profiles = self.profiles.borrow_mut();
+        profiles.retain(|value| value.id != profile.id);
+        profiles.push(profile);
+    }
+
+    // None clears every profile, return removed count
+    pub fn clear(&self, id: Option<u32>) -> usize {
+        let mut profiles = self.profiles.borrow_mut();
+        let count = profiles.len();
+        match id {
+            Some(id) => profiles.retain(|value| value.id != id),
+            None => profiles.clear(),
+        }
+        count - profiles.len()
+    }
+}
+
+// apply composite limit (and phase count when a car is plugged)
+pub(crate) fn profile_apply(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let slot = state.profile.evaluate(now_ms() / 1000);
+    state.set_limit(LimitSource::Profile, slot.limit)?;
+
+    if let Some(count) = slot.phases {
+        let switch = {
+            let data = state.data.borrow();
+            data.plugged && data.sequence.is_none() && data.phases != count
+        };
+        if switch {
+            if let Err(error) = phases_start(state, count) {
+                afb_log_msg!(Warning, None, "{}: profile phase switch fail:{}", state.uid, error);
+            }
+        }
+    }
+    Ok(())
+}
+
+struct ProfileTimerCtx {
+    state: Rc<EvseState>,
+}
+
+fn profile_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ProfileTimerCtx>()?;
+    profile_apply(&ctx.state)
+}
+
+pub(crate) fn profile_start(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    profile_apply(state)?;
+    if state.profile.config.tic == 0 {
+        return Ok(());
+    }
+    AfbTimer::new("profile-tic")
+        .set_period(state.profile.config.tic)
+        .set_decount(0)
+        .set_callback(profile_timer_cb)
+        .set_context(ProfileTimerCtx {
+            state: state.clone(),
+        })
+        .start()?;
+    Ok(())
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Virtual replug: used to recover ISO15118 sessions that fail to start. Either the
+ * firmware simulates the unplug/replug (EVSE_REPLUG_STARTED/FINISHED) or we emulate
+ * it with PWM state F. Plug events received during the sequence are not published.
+ */
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use rpmsg::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReplugMode {
+    Firmware,
+    Emulate,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct ReplugConfig {
+    pub mode: ReplugMode,
+    pub duration: u32,
+    // extra delay before closing the sequence when firmware does not report finished
+    pub timeout: u32,
+}
+
+impl ReplugConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jreplug = match jconf.optional::<JsoncObj>("replug")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let mode = match jreplug
+            .default::<&'static str>("mode", "emulate")?
+            .to_uppercase()
+            .as_str()
+        {
+            "FIRMWARE" => ReplugMode::Firmware,
+            "EMULATE" => ReplugMode::Emulate,
+            _ => {
+                return afb_error!(
+                    "replug-config-invalid",
+                    "replug mode should be firmware|emulate"
+                )
+            }
+        };
+
+        Ok(ReplugConfig {
+            mode,
+            duration: jreplug.default::<u32>("duration", 3000)?,
+            timeout: jreplug.default::<u32>("timeout", 2000)?,
+        })
+    }
+}
+
+struct ReplugTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+// emulation: end of PWM state F, restore last duty
+fn replug_restore_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ReplugTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.seq_gen.get() {
+        return Ok(());
+    }
+
+    // sequence stays active until replug_done, pwm must be written explicitly
+    if state.data.borrow().pause.is_none() {
+        state.write_pwm()?;
+    }
+
+    // keep plug events muted until the car settled
+    AfbTimer::new("replug-timeout")
+        .set_period(state.replug.timeout)
+        .set_decount(1)
+        .set_callback(replug_timeout_cb)
+        .set_context(ReplugTimerCtx {
+            state: state.clone(),
+            gen: ctx.gen,
+        })
+        .start()?;
+    Ok(())
+}
+
+fn replug_timeout_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ReplugTimerCtx>()?;
+    if ctx.gen != ctx.state.seq_gen.get() {
+        return Ok(());
+    }
+    if ctx.state.replug.mode == ReplugMode::Firmware {
+        afb_log_msg!(
+            Warning,
+            None,
+            "{}: firmware did not report replug finished",
+            ctx.state.uid
+        );
+    }
+    replug_done(&ctx.state)
+}
+
+pub(crate) fn replug_start(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    {
+        let mut data = state.data.borrow_mut();
+        if !data.plugged {
+            return afb_error!("replug-invalid-state", "replug requires a plugged car");
+        }
+        if let Some(sequence) = data.sequence {
+            return afb_error!("replug-busy", "sequence:{:?} already running", sequence);
+        }
+        data.sequence = Some(EvseSequence::Replug);
+        data.replug_plugged = true;
+        data.wakeup_watch = false;
+    }
+    let gen = state.seq_gen.get() + 1;
+    state.seq_gen.set(gen);
+
+    afb_log_msg!(
+        Notice,
+        None,
+        "{}: replug mode:{:?} duration:{}ms",
+        state.uid,
+        state.replug.mode,
+        state.replug.duration
+    );
+
+    let timer = AfbTimer::new("replug-sequence");
+    match state.replug.mode {
+        ReplugMode::Firmware => {
+            state.dev.write(&mk_replug(state.replug.duration)?)?;
+            timer
+                .set_period(state.replug.duration + state.replug.timeout)
+                .set_callback(replug_timeout_cb);
+        }
+        ReplugMode::Emulate => {
+            state.send_pwm(PwmState::F, 0.0)?;
+            timer
+                .set_period(state.replug.duration)
+                .set_callback(replug_restore_cb);
+        }
+    }
+    state.push(Iec6185Msg::Replug(true));
+
+    timer
+        .set_decount(1)
+        .set_context(ReplugTimerCtx {
+            state: state.clone(),
+            gen,
+        })
+        .start()?;
+    Ok(())
+}
+
+// called on EVSE_REPLUG_FINISHED or sequence timeout
+pub(crate) fn replug_done(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let plugged = {
+        let mut data = state.data.borrow_mut();
+        if data.sequence != Some(EvseSequence::Replug) {
+            return Ok(());
+        }
+        data.sequence = None;
+        data.replug_plugged
+    };
+    state.seq_gen.set(state.seq_gen.get() + 1);
+    state.push(Iec6185Msg::Replug(false));
+
+    // car did not come back, muted unplug was a real one
+    if !plugged {
+        afb_log_msg!(Notice, None, "{}: car left during replug", state.uid);
+        return plug_removed(state);
+    }
+    // sequence released pwm, restore client pwm
+    state.apply_pwm()
+}
+
+// plug-state events are muted while a replug sequence runs
+pub(crate) fn replug_running(state: &EvseState) -> bool {
+    state.data.borrow().sequence == Some(EvseSequence::Replug)
+}
@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Charging session tracker: a session opens on CAR_PLUGGED_IN and closes when
+ *  Plugged(false) is published (after unlock confirmation when lock is checked).
+ */
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+// wall-clock time in ms since epoch
+pub(crate) fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(value) => value.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SessionData {
+    pub current: Option<SessionInfo>,
+    pub last: Option<SessionInfo>,
+}
+
+// apply change to running session, optionally publish a session-update
+fn session_change<F>(state: &EvseState, notify: bool, change: F)
+where
+    F: FnOnce(&mut SessionInfo) -> bool,
+{
+    let update = {
+        let mut data = state.data.borrow_mut();
+        match data.session.current.as_mut() {
+            Some(session) => {
+                if change(session) && notify {
+                    Some(session.clone())
+                } else {
+                    None
+                }
+            }
+            None => None,
+        }
+    };
+    if let Some(session) = update {
+        state.push(Iec6185Msg::SessionUpdate(session));
+    }
+}
+
+pub(crate) fn session_start(state: &EvseState) {
+    let plugged = now_ms();
+    let session = {
+        let mut data = state.data.borrow_mut();
+        if let Some(session) = &data.session.current {
+            afb_log_msg!(Warning, None, "{}: session:{} still open", state.uid, session.id);
+        }
+        let session = SessionInfo {
+            id: format!("{}-{:x}", state.uid, plugged),
+            connector: state.uid.to_string(),
+            plugged,
+            authorized: None,
+            power_start: None,
+            stopped: None,
+            unplugged: None,
+            imax_offered: 0,
+            cable_imax: data.imax,
+            faults: Vec::new(),
+            stop_reason: None,
+            energy: 0.0,
+        };
+        data.session.current = Some(session.clone());
+        session
+    };
+    state.push(Iec6185Msg::SessionStart(session));
+}
+
+pub(crate) fn session_authorized(state: &EvseState, authorized: bool) {
+    // authorization is dropped on unplug, this is not a stop reason
+    if !authorized && !state.data.borrow().plugged {
+        return;
+    }
+    session_change(state, true, |session| {
+        if authorized {
+            if session.authorized.is_some() {
+                return false;
+            }
+            session.authorized = Some(now_ms());
+        } else {
+            session.stop_reason = Some(StopReason::Deauthorized);
+        }
+        true
+    });
+}
+
+// relay closed: first power or charging resumed
+pub(crate) fn session_relay(state: &EvseState, relay_on: bool) {
+    session_change(state, true, |session| {
+        if relay_on {
+            if session.power_start.is_none() {
+                session.power_start = Some(now_ms());
+            }
+            session.stopped = None;
+            session.stop_reason = None;
+        } else {
+            if session.power_start.is_none() {
+                return false;
+            }
+            session.stopped = Some(now_ms());
+        }
+        true
+    });
+}
+
+// keep first reason until charging resumes
+pub(crate) fn session_stop_reason(state: &EvseState, reason: StopReason) {
+    session_change(state, false, |session| {
+        if session.stop_reason.is_none() {
+            session.stop_reason = Some(reason);
+        }
+        true
+    });
+}
+
+pub(crate) fn session_fault(state: &EvseState, fault: &str) {
+    session_change(state, true, |session| {
+        session.faults.push(fault.to_string());
+        session.stop_reason = Some(StopReason::Fault);
+        true
+    });
+}
+
+pub(crate) fn session_cable(state: &EvseState, imax: u32) {
+    session_change(state, false, |session| {
+        session.cable_imax = imax;
+        true
+    });
+}
+
+// max current advertised through PWM during the session
+pub(crate) fn session_offer(state: &EvseState, imax: u32) {
+    session_change(state, false, |session| {
+        if imax > session.imax_offered {
+            session.imax_offered = imax;
+        }
+        true
+    });
+}
+
+// meter energy delta (Wh), published with next session update
+pub(crate) fn session_energy(state: &EvseState, energy: f64) {
+    session_change(state, false, |session| {
+        session.energy += energy;
+        true
+    });
+}
+
+// called once Plugged(false) is published
+pub(crate) fn session_end(state: &EvseState) {
+    let session = {
+        let mut data = state.data.borrow_mut();
+        let mut session = match data.session.current.take() {
+            Some(value) => value,
+            None => return,
+        };
+        session.unplugged = Some(now_ms());
+        if session.stop_reason.is_none() {
+            session.stop_reason = Some(StopReason::Unplugged);
+        }
+        if session.power_start.is_some() && session.stopped.is_none() {
+            session.stopped = session.unplugged;
+        }
+        data.session.last = Some(session.clone());
+        session
+    };
+    afb_log_msg!(Notice, None, "{}: session:{} closed", state.uid, session.id);
+    if let Some(store) = &state.store {
+        store.session(&session);
+    }
+    state.push(Iec6185Msg::SessionEnd(session));
+}
@@ -0,0 +1,721 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Reference: https://github.com/PionixPublic/ti-am62x-evse-sdk.git
+ *  following code is a RUST an API version of Pionix ti-am62x-evse-sdk user space module
+ *  interfacing through kernel RPMSG the firmware running in the MCU/M4 cortex.
+ */
+use std::cell::{Cell, OnceCell, RefCell};
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use rpmsg::prelude::*;
+use typesv4::prelude::*;
+
+// IEC61851 lowest current encoded as PWM duty (10%)
+pub(crate) const IEC_MIN_CURRENT: u32 = 6;
+
+// IEC61851 PWM encoding: 0.6A per 1% of duty cycle
+pub(crate) fn imax_to_duty(imax: u32) -> f32 {
+    imax as f32 / 60.0
+}
+
+// IEC61851 PWM decoding: 1% of duty cycle = 0.6A, X1 (100%) and sub 8% offer no current
+pub(crate) fn duty_to_imax(duty: f32) -> u32 {
+    if !(0.08..=0.97).contains(&duty) {
+        return 0;
+    }
+    (duty * 60.0).round() as u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PauseMode {
+    // no current offered, IEC61851 has no 0A duty: X1 (100%, state B1/C1)
+    Zero,
+    // move PWM to 100% duty
+    Full,
+}
+
+impl PauseMode {
+    pub fn parse(value: &str) -> Result<Self, AfbError> {
+        match value.to_uppercase().as_str() {
+            "ZERO" => Ok(PauseMode::Zero),
+            "FULL" => Ok(PauseMode::Full),
+            _ => afb_error!("pause-mode-invalid", "pause_mode should be zero|full"),
+        }
+    }
+
+    pub fn duty(&self) -> f32 {
+        match self {
+            PauseMode::Zero | PauseMode::Full => 1.0,
+        }
+    }
+}
+
+// firmware sequence temporarily owning the PWM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EvseSequence {
+    Wakeup,
+    Replug,
+    PhaseSwitch,
+}
+
+// connector runtime data (firmware events + last client commands)
+pub(crate) struct EvseData {
+    pub plugged: bool,
+    pub power_rqt: bool,
+    pub relay_on: bool,
+    pub imax: u32,
+    // last pwm/power requested by clients, restored on resume
+    pub pwm_state: PwmState,
+    pub pwm_duty: f32,
+    // last pwm written to firmware (client, limit, pause or sequence)
+    pub pwm_sent: (PwmState, f32),
+    pub power: bool,
+    pub pause: Option<PauseReason>,
+    // pause requested but car did not yet send CAR_REQUESTED_STOP_POWER
+    pub pause_pending: bool,
+    pub sequence: Option<EvseSequence>,
+    // last plug state reported while replug muted plug events
+    pub replug_plugged: bool,
+    // wakeup sequence done, waiting for CAR_REQUESTED_POWER
+    pub wakeup_watch: bool,
+    // automatic wakeup only run once per plug
+    pub wakeup_tried: bool,
+    // active phase count as confirmed by firmware
+    pub phases: u32,
+    pub phase_target: u32,
+    pub phase_step: PhaseStep,
+    // pause was started by phase switching sequence
+    pub phase_paused: bool,
+    // unplug notification waiting for unlock confirmation
+    pub unplug_pending: bool,
+    // automatic unlock waiting for relay open
+    pub unlock_pending: bool,
+    pub authorized: bool,
+    pub session: SessionData,
+    pub slac: SlacState,
+    // active firmware errors, cleared on unplug (permanent fault stays latched)
+    pub faults: Vec<String>,
+    pub firmware: Option<String>,
+    pub heartbeat_count: u32,
+    pub heartbeat_at: Option<Instant>,
+    // limits applied to pwm and limits requested (may be held by random delay)
+    pub limits: EvseLimits,
+    pub limits_next: EvseLimits,
+    // last current advertised through PWM (published on 'limit' channel)
+    pub offered: Option<u32>,
+    // car stopped charging by itself, it no longer takes part in budget sharing
+    pub ev_done: bool,
+    // site budget cannot grant minimum current, connector stays paused
+    pub budget_denied: bool,
+}
+
+// shared between firmware async callback, timers and verbs
+pub(crate) struct EvseState {
+    pub uid: &'static str,
+    pub apiv4: AfbApiV4,
+    pub dev: Rc<TiRpmsg>,
+    pub evt: &'static AfbEvent,
+    pub max_current: u32,
+    pub priority: u32,
+    // shared with other connectors, installed once every connector is registered
+    pub budget: OnceCell<Rc<PowerBudget>>,
+    pub ext_limit: ExtLimitCtx,
+    pub profile: ProfileCtx,
+    pub delay: DelayCtx,
+    pub surplus: SurplusCtx,
+    pub meter: Option<MeterCtx>,
+    pub thermal: Option<ThermalCtx>,
+    // last published ocpp status
+    pub ocpp: RefCell<Option<OcppConnector>>,
+    pub channels: EvseChannels,
+    pub envelope: bool,
+    // last envelope sequence number published on 'iec'
+    pub evt_seq: Cell<u64>,
+    // firmware event being processed, reported within envelope
+    pub evt_iec: Cell<Option<&'static str>>,
+    pub started: Instant,
+    pub history: EvseHistory,
+    pub pause_mode: PauseMode,
+    pub pause_timeout: u32,
+    pub pause_gen: Cell<u32>,
+    pub wakeup: WakeupConfig,
+    pub replug: ReplugConfig,
+    pub phases: PhasesConfig,
+    pub lock: LockCtx,
+    pub auth: AuthConfig,
+    pub auth_gen: Cell<u32>,
+    pub store: Option<EventStore>,
+    pub seq_gen: Cell<u32>,
+    pub data: RefCell<EvseData>,
+}
+
+impl EvseState {
+    pub fn new(
+        apiv4: AfbApiV4,
+        dev: Rc<TiRpmsg>,
+        evt: &'static AfbEvent,
+        config: &ApiUserData,
+    ) -> Rc<Self> {
+        let lock = LockCtx::new(apiv4, dev.clone(), config.lock.clone());
+        Rc::new(EvseState {
+            uid: config.uid,
+            apiv4,
+            dev,
+            evt,
+            max_current: config.max_current,
+            priority: config.priority,
+            budget: OnceCell::new(),
+            ext_limit: ExtLimitCtx::new(config.external_limit),
+            profile: config.profile.clone(),
+            delay: DelayCtx::new(config.random_delay),
+            surplus: SurplusCtx::new(config.surplus),
+            meter: config.meter.map(MeterCtx::new),
+            thermal: config.thermal.clone().map(ThermalCtx::new),
+            ocpp: RefCell::new(None),
+            channels: EvseChannels::new(config.prefix),
+            envelope: config.envelope,
+            evt_seq: Cell::new(0),
+            evt_iec: Cell::new(None),
+            started: Instant::now(),
+            history: EvseHistory::new(config.history),
+            pause_mode: config.pause_mode,
+            pause_timeout: config.pause_timeout,
+            pause_gen: Cell::new(0),
+            wakeup: config.wakeup,
+            replug: config.replug,
+            phases: config.phases,
+            lock,
+            auth: config.auth.clone(),
+            auth_gen: Cell::new(0),
+            store: config.store.clone().map(EventStore::new),
+            seq_gen: Cell::new(0),
+            data: RefCell::new(EvseData {
+                plugged: false,
+                power_rqt: false,
+                relay_on: false,
+                imax: 0,
+                pwm_state: PwmState::Off,
+                pwm_duty: 0.0,
+                pwm_sent: (PwmState::Off, 0.0),
+                power: false,
+                pause: None,
+                pause_pending: false,
+                sequence: None,
+                replug_plugged: false,
+                wakeup_watch: false,
+                wakeup_tried: false,
+                phases: config.phases.count,
+                phase_target: config.phases.count,
+                phase_step: PhaseStep::Idle,
+                phase_paused: false,
+                unplug_pending: false,
+                unlock_pending: false,
+                authorized: config.auth.mode == AuthMode::Free,
+                session: SessionData::default(),
+                slac: SlacState::Udf,
+                faults: Vec::new(),
+                firmware: None,
+                heartbeat_count: 0,
+                heartbeat_at: None,
+                limits: EvseLimits::default(),
+                limits_next: EvseLimits::default(),
+                offered: None,
+                ev_done: false,
+                budget_denied: false,
+            }),
+        })
+    }
+
+    fn wrap(&self, seq: u64, msg: Iec6185Msg) -> EvseEvent {
+        EvseEvent {
+            seq,
+            ts: now_ms(),
+            uptime: self.started.elapsed().as_millis() as u64,
+            connector: self.uid.to_string(),
+            version: EVSE_EVENT_VERSION,
+            iec: self.evt_iec.get().map(|value| value.to_string()),
+            msg,
+        }
+    }
+
+    pub fn push(&self, msg: Iec6185Msg) {
+        let channel = EvseChannel::of(&msg);
+        // 'iec' and each channel are numbered independently, mcu events are not on 'iec'
+        let seq = self.channels.next_seq(channel);
+        let iec_seq = match channel {
+            EvseChannel::Mcu => None,
+            _ => {
+                self.evt_seq.set(self.evt_seq.get() + 1);
+                Some(self.evt_seq.get())
+            }
+        };
+
+        match iec_seq {
+            None => afb_log_msg!(Debug, None, "JobPost push mcu event:{:?}", msg),
+            Some(iec_seq) => afb_log_msg!(
+                Notice,
+                None,
+                "JobPost push event:{:?} seq:{} {}:{}",
+                msg,
+                iec_seq,
+                channel.name(),
+                seq
+            ),
+        }
+        self.history.published(channel, &msg);
+
+        if !self.envelope {
+            if iec_seq.is_some() {
+                self.evt.push(msg.clone());
+            }
+            self.channels.get(channel).push(msg);
+        } else {
+            if let Some(iec_seq) = iec_seq {
+                self.evt.push(self.wrap(iec_seq, msg.clone()));
+            }
+            self.channels.get(channel).push(self.wrap(seq, msg));
+        }
+
+        // every state change is followed by an event
+        if channel != EvseChannel::Mcu && channel != EvseChannel::Ocpp {
+            ocpp_update(self);
+        }
+    }
+
+    // return true when cable imax changed
+    pub fn set_imax(&self, imax: u32) -> bool {
+        {
+            let mut data = self.data.borrow_mut();
+            if data.imax == imax {
+                return false;
+            }
+            data.imax = imax;
+        }
+        session_cable(self, imax);
+        true
+    }
+
+    // when paused or within a sequence pwm is only stored and applied later
+    pub fn set_pwm(&self, state: PwmState, duty: f32) -> Result<(), AfbError> {
+        {
+            let mut data = self.data.borrow_mut();
+            data.pwm_state = state;
+            data.pwm_duty = duty;
+        }
+        self.apply_pwm()
+    }
+
+    // client pwm capped by active current limits, a limit under IEC minimum pauses
+    pub fn pwm_target(&self) -> (PwmState, f32) {
+        let data = self.data.borrow();
+        match (data.pwm_state, data.limits.current()) {
+            (PwmState::On, Some(limit)) if limit < IEC_MIN_CURRENT => {
+                (PwmState::On, self.pause_mode.duty())
+            }
+            (PwmState::On, Some(limit)) => (PwmState::On, data.pwm_duty.min(imax_to_duty(limit))),
+            (state, _) => (state, data.pwm_duty),
+        }
+    }
+
+    // unconditionally send last client pwm (end of pause or sequence)
+    pub fn write_pwm(&self) -> Result<(), AfbError> {
+        let (state, duty) = self.pwm_target();
+        self.send_pwm(state, duty)
+    }
+
+    // every firmware pwm command goes through here, status and meter rely on it
+    pub fn send_pwm(&self, state: PwmState, duty: f32) -> Result<(), AfbError> {
+        self.dev.write(&mk_pwm(&state, duty)?)?;
+        let offered = match state {
+            PwmState::On => Some(duty_to_imax(duty)),
+            _ => None,
+        };
+        let changed = {
+            let mut data = self.data.borrow_mut();
+            data.pwm_sent = (state, duty);
+            let changed = data.offered != offered;
+            data.offered = offered;
+            changed
+        };
+        if let (true, Some(imax)) = (changed, offered) {
+            session_offer(self, imax);
+            self.push(Iec6185Msg::Limit(imax));
+        }
+        Ok(())
+    }
+
+    pub fn apply_pwm(&self) -> Result<(), AfbError> {
+        let paused = {
+            let data = self.data.borrow();
+            data.pause.is_some() || data.sequence.is_some()
+        };
+        if paused {
+            return Ok(());
+        }
+        self.write_pwm()
+    }
+
+    // update one limit source and refresh pwm when effective limit changed
+    pub fn set_limit(
+        self: &Rc<Self>,
+        source: LimitSource,
+        value: Option<u32>,
+    ) -> Result<(), AfbError> {
+        let hold = {
+            let mut data = self.data.borrow_mut();
+            if !data.limits_next.set(source, value) {
+                return Ok(());
+            }
+            // only raising current is held, reducing it is never delayed
+            source.randomized()
+                && self.delay.config.on_limit
+                && data.plugged
+                && limit_raised(data.limits_next.current(), data.limits.current())
+        };
+        afb_log_msg!(Debug, None, "{}: limit {:?}={:?}", self.uid, source, value);
+
+        if hold {
+            delay_start(self)?;
+            if self.delay.active() {
+                return Ok(());
+            }
+            return self.limits_release();
+        }
+        self.set_limit_now(source, value)
+    }
+
+    // apply a limit source immediately, bypassing random delay (safety/failsafe)
+    pub fn set_limit_now(&self, source: LimitSource, value: Option<u32>) -> Result<(), AfbError> {
+        let changed = {
+            let mut data = self.data.borrow_mut();
+            data.limits_next.set(source, value);
+            let current = data.limits.current();
+            data.limits.set(source, value);
+            data.limits.current() != current
+        };
+        if !changed {
+            return Ok(());
+        }
+        self.apply_pwm()
+    }
+
+    // apply every requested limit (end of random delay)
+    pub fn limits_release(&self) -> Result<(), AfbError> {
+        let changed = {
+            let mut data = self.data.borrow_mut();
+            let current = data.limits.current();
+            data.limits = data.limits_next.clone();
+            data.limits.current() != current
+        };
+        if !changed {
+            return Ok(());
+        }
+        self.apply_pwm()
+    }
+
+    // unconditionally stop pwm and pending sequence (car unplugged)
+    pub fn pwm_off(&self) -> Result<(), AfbError> {
+        {
+            let mut data = self.data.borrow_mut();
+            data.pwm_state = PwmState::Off;
+            data.pwm_duty = 0.0;
+            data.sequence = None;
+            data.wakeup_watch = false;
+        }
+        self.seq_gen.set(self.seq_gen.get() + 1);
+        self.send_pwm(PwmState::Off, 0.0)
+    }
+
+    // when paused or lock not confirmed power-on is only stored and applied later
+    pub fn set_power(&self, allow: bool) -> Result<(), AfbError> {
+        self.data.borrow_mut().power = allow;
+        if allow {
+            return self.apply_power();
+        }
+        self.dev.write(&mk_power(false)?)
+    }
+
+    // send stored AllowPowerOn when every gate is open
+    pub fn apply_power(&self) -> Result<(), AfbError> {
+        let (ready, authorized, overcurrent) = {
+            let data = self.data.borrow();
+            (
+                data.power && data.pause.is_none(),
+                data.authorized,
+                data.faults.iter().any(|value| value == OVERCURRENT_FAULT),
+            )
+        };
+
+        if !ready {
+            return Ok(());
+        }
+        if !authorized {
+            afb_log_msg!(Notice, None, "{}: power deferred until authorized", self.uid);
+            return Ok(());
+        }
+        // latched until unplug
+        if overcurrent {
+            afb_log_msg!(Warning, None, "{}: power refused after EV overcurrent", self.uid);
+            return Ok(());
+        }
+        // latched until temperature back under cut-off minus hysteresis
+        if self.overtemp_cutoff() {
+            afb_log_msg!(Warning, None, "{}: power refused at temperature cut-off", self.uid);
+            return Ok(());
+        }
+        if !self.lock.confirmed() {
+            afb_log_msg!(Notice, None, "{}: power deferred until lock confirmed", self.uid);
+            return Ok(());
+        }
+        if self.delay.active() {
+            afb_log_msg!(Notice, None, "{}: power deferred by random delay", self.uid);
+            return Ok(());
+        }
+        self.dev.write(&mk_power(true)?)
+    }
+
+    pub fn heartbeat(&self) {
+        let count = {
+            let mut data = self.data.borrow_mut();
+            data.heartbeat_count = data.heartbeat_count.wrapping_add(1);
+            data.heartbeat_at = Some(Instant::now());
+            data.heartbeat_count
+        };
+        self.push(Iec6185Msg::Heartbeat(count));
+    }
+
+    pub fn fault_set(&self, fault: &str) {
+        let mut data = self.data.borrow_mut();
+        if !data.faults.iter().any(|value| value == fault) {
+            data.faults.push(fault.to_string());
+        }
+    }
+
+    pub fn faults_clear(&self) {
+        self.data
+            .borrow_mut()
+            .faults
+            .retain(|value| value == Iec61851Event::PermanentFault.as_str_name());
+    }
+
+    pub fn status(&self) -> EvseStatus {
+        let data = self.data.borrow();
+        let state = if !data.faults.is_empty() {
+            ConnectorState::Faulted
+        } else if data.pause.is_some() {
+            ConnectorState::Paused
+        } else if data.relay_on {
+            ConnectorState::Charging
+        } else if data.plugged {
+            ConnectorState::Plugged
+        } else {
+            ConnectorState::Idle
+        };
+
+        EvseStatus {
+            connector: self.uid.to_string(),
+            state,
+            plugged: data.plugged,
+            power_rqt: data.power_rqt,
+            cable_imax: data.imax,
+            pwm_state: data.pwm_sent.0.as_str_name().to_string(),
+            pwm_duty: data.pwm_sent.1,
+            power_allowed: data.power,
+            relay_on: data.relay_on,
+            slac_state: data.slac.as_str_name().to_string(),
+            lock: self.lock.status.get(),
+            authorized: data.authorized,
+            pause: data.pause,
+            phases: data.phases,
+            limit: data.limits.current(),
+            limit_failsafe: self.ext_limit.failsafe.get(),
+            random_delay: self.delay.remaining(),
+            current: self
+                .meter
+                .as_ref()
+                .and_then(|meter| meter.last.borrow().as_ref().map(|last| last.current.clone())),
+            energy: data.session.current.as_ref().map(|session| session.energy),
+            heartbeat_count: data.heartbeat_count,
+            heartbeat_age: data
+                .heartbeat_at
+                .map(|value| value.elapsed().as_millis() as u64),
+            faults: data.faults.clone(),
+            firmware: data.firmware.clone(),
+            ocpp: ocpp_status(self),
+        }
+    }
+
+    pub fn overtemp_cutoff(&self) -> bool {
+        match &self.thermal {
+            Some(thermal) => thermal.cutoff(),
+            None => false,
+        }
+    }
+
+    // pause reason that must outlive any other pause
+    pub fn pause_held(&self) -> Option<PauseReason> {
+        if self.overtemp_cutoff() {
+            return Some(PauseReason::Overtemperature);
+        }
+        if self.data.borrow().budget_denied {
+            return Some(PauseReason::Budget);
+        }
+        None
+    }
+
+    // open relay while keeping client power request for later
+    pub fn withdraw_power(&self) -> Result<(), AfbError> {
+        self.dev.write(&mk_power(false)?)
+    }
+}
+
+struct PauseTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+// car did not stop within pause_timeout, withdraw power anyway
+fn pause_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PauseTimerCtx>()?;
+    if ctx.gen != ctx.state.pause_gen.get() || !ctx.state.data.borrow().pause_pending {
+        return Ok(());
+    }
+    afb_log_msg!(
+        Warning,
+        None,
+        "{}: car ignored pause request after {}ms",
+        ctx.state.uid,
+        ctx.state.pause_timeout
+    );
+    pause_done(&ctx.state)
+}
+
+// withdraw AllowPowerOn and notify pause reason
+fn pause_done(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let reason = {
+        let mut data = state.data.borrow_mut();
+        data.pause_pending = false;
+        match data.pause {
+            Some(reason) => reason,
+            None => return Ok(()),
+        }
+    };
+    state.dev.write(&mk_power(false)?)?;
+    state.push(Iec6185Msg::Paused(reason));
+    Ok(())
+}
+
+pub(crate) fn pause_start(state: &Rc<EvseState>, reason: PauseReason) -> Result<(), AfbError> {
+    let (pending, pwm_off) = {
+        let mut data = state.data.borrow_mut();
+        if let Some(current) = data.pause {
+            return afb_error!(
+                "pause-already-set",
+                "connector already paused reason:{:?}",
+                current
+            );
+        }
+        data.pause = Some(reason);
+        data.pause_pending = data.power_rqt;
+        (
+            data.pause_pending,
+            !data.plugged || data.pwm_sent.0 == PwmState::Off,
+        )
+    };
+    let gen = state.pause_gen.get() + 1;
+    state.pause_gen.set(gen);
+
+    // never start PWM oscillator for a pause, resume restores client pwm
+    if !pwm_off {
+        state.send_pwm(PwmState::On, state.pause_mode.duty())?;
+    }
+
+    // car is drawing power, wait for it to stop before opening relay
+    if pending {
+        AfbTimer::new("pause-timeout")
+            .set_period(state.pause_timeout)
+            .set_decount(1)
+            .set_callback(pause_timer_cb)
+            .set_context(PauseTimerCtx {
+                state: state.clone(),
+                gen,
+            })
+            .start()?;
+        return Ok(());
+    }
+    pause_done(state)
+}
+
+// called when car acknowledged pause with CAR_REQUESTED_STOP_POWER or left
+pub(crate) fn pause_stopped(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    if !state.data.borrow().pause_pending {
+        return Ok(());
+    }
+    pause_done(state)
+}
+
+// car left: session pauses end silently, operator pause keeps connector unavailable
+pub(crate) fn pause_unplugged(state: &Rc<EvseState>) {
+    {
+        let mut data = state.data.borrow_mut();
+        data.pause_pending = false;
+        if data.pause != Some(PauseReason::Operator) {
+            data.pause = None;
+        }
+    }
+    state.pause_gen.set(state.pause_gen.get() + 1);
+}
+
+pub(crate) fn pause_resume(state: &Rc<EvseState>) -> Result<PauseReason, AfbError> {
+    // temperature cut-off or budget refusal takes over any ending pause
+    if let Some(held) = state.pause_held() {
+        let previous = {
+            let mut data = state.data.borrow_mut();
+            let previous = match data.pause {
+                Some(reason) => reason,
+                None => return afb_error!("pause-not-set", "connector is not paused"),
+            };
+            data.pause = Some(held);
+            data.pause_pending = false;
+            previous
+        };
+        if previous != held {
+            state.push(Iec6185Msg::Paused(held));
+        }
+        return Ok(previous);
+    }
+
+    let reason = {
+        let mut data = state.data.borrow_mut();
+        let reason = match data.pause.take() {
+            Some(reason) => reason,
+            None => return afb_error!("pause-not-set", "connector is not paused"),
+        };
+        data.pause_pending = false;
+        reason
+    };
+    state.pause_gen.set(state.pause_gen.get() + 1);
+
+    // restore last limit (unless a sequence owns pwm) and power authorization
+    state.apply_pwm()?;
+    state.apply_power()?;
+    state.push(Iec6185Msg::Resumed(reason));
+    Ok(reason)
+}
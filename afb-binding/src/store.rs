@@ -0,0 +1,199 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Append-only JSON lines store for closed sessions and faults. When the file
+ *  grows over 'rotate' bytes it is renamed path.1 (path.1 -> path.2 ...) and only
+ *  'keep' rotated files are preserved.
+ */
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use serde::{Deserialize, Serialize};
+use typesv4::prelude::*;
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum StoreRecord<'a> {
+    Session {
+        ts: u64,
+        connector: &'a str,
+        session: &'a SessionInfo,
+    },
+    Fault {
+        ts: u64,
+        connector: &'a str,
+        fault: &'a str,
+    },
+}
+
+// only fields used for filtering are decoded
+#[derive(Deserialize)]
+struct StoreHeader {
+    ts: u64,
+    kind: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct StoreConfig {
+    pub path: &'static str,
+    pub rotate: u64,
+    pub keep: u32,
+}
+
+impl StoreConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Option<Self>, AfbError> {
+        let jstore = match jconf.optional::<JsoncObj>("store")? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let keep = jstore.default::<u32>("keep", 3)?;
+        if keep == 0 {
+            return afb_error!("store-config-invalid", "store keep should be > 0");
+        }
+
+        Ok(Some(StoreConfig {
+            path: jstore.get::<&'static str>("path")?,
+            rotate: jstore.default::<u64>("rotate", 1024 * 1024)?,
+            keep,
+        }))
+    }
+}
+
+pub(crate) struct StoreQuery<'a> {
+    pub from: u64,
+    pub to: u64,
+    pub kind: Option<&'a str>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+pub(crate) struct EventStore {
+    config: StoreConfig,
+}
+
+impl EventStore {
+    pub fn new(config: StoreConfig) -> Self {
+        EventStore { config }
+    }
+
+    fn rotated(&self, idx: u32) -> String {
+        format!("{}.{}", self.config.path, idx)
+    }
+
+    fn rotate(&self) -> Result<(), std::io::Error> {
+        let size = match fs::metadata(self.config.path) {
+            Ok(meta) => meta.len(),
+            Err(_) => return Ok(()),
+        };
+        if size < self.config.rotate {
+            return Ok(());
+        }
+
+        let _ = fs::remove_file(self.rotated(self.config.keep));
+        for idx in (1..self.config.keep).rev() {
+            let _ = fs::rename(self.rotated(idx), self.rotated(idx + 1));
+        }
+        fs::rename(self.config.path, self.rotated(1))
+    }
+
+    fn append(&self, record: &StoreRecord) {
+        let line = match serde_json::to_string(record) {
+            Ok(value) => value,
+            Err(error) => {
+                afb_log_msg!(Error, None, "store encode fail:{}", error);
+                return;
+            }
+        };
+
+        let status = self.rotate().and_then(|_| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.config.path)?;
+            writeln!(file, "{}", line)
+        });
+
+        // storage problems never block the charging process
+        if let Err(error) = status {
+            afb_log_msg!(Error, None, "store {} write fail:{}", self.config.path, error);
+        }
+    }
+
+    pub fn session(&self, session: &SessionInfo) {
+        self.append(&StoreRecord::Session {
+            ts: session.unplugged.unwrap_or(session.plugged),
+            connector: &session.connector,
+            session,
+        });
+    }
+
+    pub fn fault(&self, connector: &str, ts: u64, fault: &str) {
+        self.append(&StoreRecord::Fault {
+            ts,
+            connector,
+            fault,
+        });
+    }
+
+    // return total matching records and requested page as a json array string
+    pub fn query(&self, query: &StoreQuery) -> (usize, String) {
+        // oldest rotated file first
+        let mut files: Vec<String> = (1..=self.config.keep)
+            .rev()
+            .map(|idx| self.rotated(idx))
+            .collect();
+        files.push(self.config.path.to_string());
+
+        let mut total = 0;
+        let mut page = Vec::new();
+        for path in files {
+            let content = match fs::read_to_string(&path) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            for line in content.lines() {
+                let header = match serde_json::from_str::<StoreHeader>(line) {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+                if header.ts < query.from || header.ts > query.to {
+                    continue;
+                }
+                if let Some(kind) = query.kind {
+                    if header.kind != kind {
+                        continue;
+                    }
+                }
+                if total >= query.offset && page.len() < query.limit {
+                    page.push(line.to_string());
+                }
+                total += 1;
+            }
+        }
+        (total, format!("[{}]", page.join(",")))
+    }
+}
+
+// faults are stored even when no session is running
+pub(crate) fn store_fault(state: &EvseState, fault: &str) {
+    if let Some(store) = &state.store {
+        store.fault(state.uid, now_ms(), fault);
+    }
+}
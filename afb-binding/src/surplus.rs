@@ -0,0 +1,244 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * PV surplus charging: grid meter events {"power":W} (import > 0, export < 0) drive
+ *  the charging current. EV draw is estimated from advertised current, the target
+ *  follows export with hysteresis, and charging pauses when surplus stays below the
+ *  minimum current for 'pause_delay'. Pause/resume respect min_on/min_off times.
+ */
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy)]
+pub(crate) struct SurplusConfig {
+    // grid meter api/event, empty api: readings only come from 'surplus' verb
+    pub api: &'static str,
+    pub event: &'static str,
+    pub subscribe: &'static str,
+    // enabled at startup, grid meter api is only required when set
+    pub enabled: bool,
+    // nominal phase voltage (V)
+    pub voltage: u32,
+    // IEC61851 minimum charging current (A)
+    pub min: u32,
+    // ignore target changes smaller than hysteresis (A)
+    pub hysteresis: u32,
+    // minimum charging/paused time in seconds
+    pub min_on: u32,
+    pub min_off: u32,
+    // surplus below min for pause_delay seconds pauses charging
+    pub pause_delay: u32,
+    // select 1p/3p from available surplus
+    pub phase_switch: bool,
+}
+
+impl SurplusConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jsurplus = match jconf.optional::<JsoncObj>("surplus")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let api = jsurplus.default::<&'static str>("api", "")?;
+        let config = SurplusConfig {
+            api,
+            event: match api {
+                "" => "",
+                _ => jsurplus.get::<&'static str>("event")?,
+            },
+            subscribe: jsurplus.default::<&'static str>("subscribe", "subscribe")?,
+            enabled: jsurplus.default::<bool>("enabled", false)?,
+            voltage: jsurplus.default::<u32>("voltage", 230)?,
+            min: jsurplus.default::<u32>("min", 6)?,
+            hysteresis: jsurplus.default::<u32>("hysteresis", 1)?,
+            min_on: jsurplus.default::<u32>("min_on", 60)?,
+            min_off: jsurplus.default::<u32>("min_off", 120)?,
+            pause_delay: jsurplus.default::<u32>("pause_delay", 60)?,
+            phase_switch: jsurplus.default::<bool>("phase_switch", false)?,
+        };
+        if config.voltage == 0 || config.min == 0 {
+            return afb_error!("surplus-config-invalid", "surplus voltage/min should be > 0");
+        }
+        Ok(config)
+    }
+
+    // api required at binding finalize time, disabled mode only uses 'surplus' verb
+    pub fn required_api(&self) -> Option<&'static str> {
+        match self.api {
+            _ if !self.enabled => None,
+            "" => None,
+            api => Some(api),
+        }
+    }
+}
+
+pub(crate) struct SurplusCtx {
+    pub config: SurplusConfig,
+    pub enabled: Cell<bool>,
+    // last grid power (W)
+    pub grid: Cell<Option<i32>>,
+    pub target: Cell<Option<u32>>,
+    below: Cell<Option<Instant>>,
+    // last surplus pause/resume
+    toggled: Cell<Option<Instant>>,
+}
+
+impl SurplusCtx {
+    pub fn new(config: SurplusConfig) -> Self {
+        SurplusCtx {
+            config,
+            enabled: Cell::new(config.enabled),
+            grid: Cell::new(None),
+            target: Cell::new(None),
+            below: Cell::new(None),
+            toggled: Cell::new(None),
+        }
+    }
+
+    fn toggled_for(&self, secs: u32) -> bool {
+        match self.toggled.get() {
+            Some(at) => at.elapsed() >= Duration::from_secs(secs as u64),
+            None => true,
+        }
+    }
+}
+
+// update surplus limit unless change is within hysteresis
+fn surplus_limit(state: &Rc<EvseState>, amps: u32) -> Result<(), AfbError> {
+    let ctx = &state.surplus;
+    if let Some(target) = ctx.target.get() {
+        if target.abs_diff(amps) < ctx.config.hysteresis {
+            return Ok(());
+        }
+    }
+    ctx.target.set(Some(amps));
+    state.set_limit(LimitSource::Surplus, Some(amps))
+}
+
+// new grid meter reading
+pub(crate) fn surplus_update(state: &Rc<EvseState>, grid: i32) -> Result<(), AfbError> {
+    let ctx = &state.surplus;
+    let config = ctx.config;
+    ctx.grid.set(Some(grid));
+    if !ctx.enabled.get() {
+        return Ok(());
+    }
+
+    let (plugged, draw, phases, pause, sequence) = {
+        let data = state.data.borrow();
+        let draw = match (data.relay_on, data.offered) {
+            (true, Some(imax)) => imax * data.phases * config.voltage,
+            _ => 0,
+        };
+        (data.plugged, draw, data.phases, data.pause, data.sequence)
+    };
+    if !plugged || sequence.is_some() {
+        ctx.below.set(None);
+        return Ok(());
+    }
+
+    // export plus what the car is already taking
+    let surplus = (draw as i64 - grid as i64).max(0) as u32;
+    let amps = (surplus / (config.voltage * phases)).min(state.max_current);
+    afb_log_msg!(Debug, None, "{}: surplus {}W -> {}A", state.uid, surplus, amps);
+
+    if config.phase_switch && pause.is_none() {
+        let single = surplus / config.voltage;
+        let count = match phases {
+            3 if amps < config.min && single >= config.min => 1,
+            1 if surplus / (3 * config.voltage) >= config.min + config.hysteresis => 3,
+            _ => phases,
+        };
+        if count != phases && ctx.toggled_for(config.min_on) {
+            afb_log_msg!(Notice, None, "{}: surplus switching to {} phase(s)", state.uid, count);
+            ctx.toggled.set(Some(Instant::now()));
+            return phases_start(state, count);
+        }
+    }
+
+    if amps < config.min {
+        surplus_limit(state, config.min)?;
+        let below = match ctx.below.get() {
+            Some(value) => value,
+            None => {
+                let now = Instant::now();
+                ctx.below.set(Some(now));
+                now
+            }
+        };
+        if pause.is_none()
+            && below.elapsed() >= Duration::from_secs(config.pause_delay as u64)
+            && ctx.toggled_for(config.min_on)
+        {
+            afb_log_msg!(Notice, None, "{}: not enough surplus, pausing", state.uid);
+            ctx.toggled.set(Some(Instant::now()));
+            pause_start(state, PauseReason::Surplus)?;
+        }
+        return Ok(());
+    }
+    ctx.below.set(None);
+
+    surplus_limit(state, amps)?;
+    if pause == Some(PauseReason::Surplus) && ctx.toggled_for(config.min_off) {
+        ctx.toggled.set(Some(Instant::now()));
+        pause_resume(state)?;
+    }
+    Ok(())
+}
+
+// car left or mode disabled: surplus no longer limits nor pauses connector
+pub(crate) fn surplus_reset(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    let ctx = &state.surplus;
+    ctx.target.set(None);
+    ctx.below.set(None);
+    ctx.toggled.set(None);
+    state.set_limit(LimitSource::Surplus, None)?;
+    if state.data.borrow().pause == Some(PauseReason::Surplus) {
+        pause_resume(state)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn surplus_enable(state: &Rc<EvseState>, enable: bool) -> Result<(), AfbError> {
+    state.surplus.enabled.set(enable);
+    if !enable {
+        return surplus_reset(state);
+    }
+    match state.surplus.grid.get() {
+        Some(grid) => surplus_update(state, grid),
+        None => Ok(()),
+    }
+}
+
+pub(crate) struct SurplusEvtCtx {
+    pub state: Rc<EvseState>,
+}
+
+// grid meter event {"power":W}
+pub(crate) fn surplus_event_cb(
+    _evt: &AfbEventMsg,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<SurplusEvtCtx>()?;
+    let jevent = args.get::<JsoncObj>(0)?;
+    surplus_update(&ctx.state, jevent.get::<i32>("power")?)
+}
@@ -0,0 +1,364 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Temperature derating: each sensor (api event, hwmon sysfs file, firmware telemetry)
+ *  has a threshold list [{"temp":°C,"amps":A}] and a cut-off temperature pausing the
+ *  connector. A sensor only leaves a derating step once 'hysteresis' °C below it.
+ *  Cut-off is latched: power stays refused and the connector paused until every sensor
+ *  is back below cut-off minus hysteresis. An unreadable hwmon sensor counts as cut-off.
+ *  firmware sensors are reserved until the firmware publishes temperatures.
+ */
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone)]
+pub(crate) enum ThermalSource {
+    // api event {key: °C}
+    Event {
+        api: &'static str,
+        event: &'static str,
+        subscribe: &'static str,
+        key: &'static str,
+    },
+    // sysfs file in milli °C
+    Hwmon { path: &'static str },
+    Firmware,
+}
+
+#[derive(Clone)]
+pub(crate) struct ThermalSensor {
+    pub uid: &'static str,
+    pub source: ThermalSource,
+    // (°C, A) sorted by temperature
+    pub thresholds: Vec<(f64, u32)>,
+    pub cutoff: f64,
+}
+
+impl ThermalSensor {
+    fn from_jsonc(jsensor: &JsoncObj) -> Result<Self, AfbError> {
+        let uid = jsensor.get::<&'static str>("uid")?;
+        let source = match jsensor.get::<&'static str>("source")?.to_uppercase().as_str() {
+            "EVENT" => ThermalSource::Event {
+                api: jsensor.get::<&'static str>("api")?,
+                event: jsensor.get::<&'static str>("event")?,
+                subscribe: jsensor.default::<&'static str>("subscribe", "subscribe")?,
+                key: jsensor.default::<&'static str>("key", "temperature")?,
+            },
+            "HWMON" => ThermalSource::Hwmon {
+                path: jsensor.get::<&'static str>("path")?,
+            },
+            "FIRMWARE" => ThermalSource::Firmware,
+            _ => {
+                return afb_error!(
+                    "thermal-config-invalid",
+                    "sensor:{} source should be event|hwmon|firmware",
+                    uid
+                )
+            }
+        };
+
+        let mut thresholds = Vec::new();
+        if let Some(jcurve) = jsensor.optional::<JsoncObj>("curve")? {
+            for idx in 0..jcurve.count()? {
+                let jstep = jcurve.index::<JsoncObj>(idx)?;
+                let amps = jstep.get::<u32>("amps")?;
+                if amps < IEC_MIN_CURRENT {
+                    return afb_error!(
+                        "thermal-config-invalid",
+                        "sensor:{} curve amps should be >= {}, use cutoff to stop charging",
+                        uid,
+                        IEC_MIN_CURRENT
+                    );
+                }
+                thresholds.push((jstep.get::<f64>("temp")?, amps));
+            }
+        }
+        thresholds.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if thresholds.windows(2).any(|pair| pair[1].1 > pair[0].1) {
+            return afb_error!(
+                "thermal-config-invalid",
+                "sensor:{} curve amps should decrease with temperature",
+                uid
+            );
+        }
+
+        let cutoff = jsensor.get::<f64>("cutoff")?;
+        if thresholds.last().is_some_and(|last| last.0 >= cutoff) {
+            return afb_error!("thermal-config-invalid", "sensor:{} cutoff under curve", uid);
+        }
+
+        Ok(ThermalSensor {
+            uid,
+            source,
+            thresholds,
+            cutoff,
+        })
+    }
+
+    // 0: no derating, 1..=len: curve step, len+1: cut-off
+    fn level(&self, celsius: f64) -> usize {
+        if celsius >= self.cutoff {
+            return self.thresholds.len() + 1;
+        }
+        self.thresholds.iter().filter(|step| celsius >= step.0).count()
+    }
+
+    // cut-off publishes no limit, it pauses the connector instead
+    fn amps(&self, level: usize) -> Option<u32> {
+        match level {
+            0 => None,
+            level if level > self.thresholds.len() => None,
+            level => Some(self.thresholds[level - 1].1),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ThermalConfig {
+    pub sensors: Vec<ThermalSensor>,
+    pub hysteresis: f64,
+    // hwmon polling period in ms
+    pub tic: u32,
+}
+
+impl ThermalConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Option<Self>, AfbError> {
+        let jthermal = match jconf.optional::<JsoncObj>("thermal")? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let jsensors = jthermal.get::<JsoncObj>("sensors")?;
+        let mut sensors = Vec::new();
+        for idx in 0..jsensors.count()? {
+            sensors.push(ThermalSensor::from_jsonc(&jsensors.index::<JsoncObj>(idx)?)?);
+        }
+
+        Ok(Some(ThermalConfig {
+            sensors,
+            hysteresis: jthermal.default::<f64>("hysteresis", 5.0)?,
+            tic: jthermal.default::<u32>("tic", 5000)?,
+        }))
+    }
+
+    // (api, subscribe verb) of event sensors
+    pub fn apis(&self) -> Vec<(&'static str, &'static str)> {
+        self.sensors
+            .iter()
+            .filter_map(|sensor| match sensor.source {
+                ThermalSource::Event { api, subscribe, .. } => Some((api, subscribe)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct ThermalCtx {
+    pub config: ThermalConfig,
+    levels: RefCell<Vec<usize>>,
+    derating: Cell<bool>,
+    cutoff: Cell<bool>,
+}
+
+impl ThermalCtx {
+    pub fn new(config: ThermalConfig) -> Self {
+        ThermalCtx {
+            levels: RefCell::new(vec![0; config.sensors.len()]),
+            config,
+            derating: Cell::new(false),
+            cutoff: Cell::new(false),
+        }
+    }
+
+    // at least one sensor above its first threshold
+    pub fn derating(&self) -> bool {
+        self.derating.get()
+    }
+
+    // at least one sensor at cut-off (cleared with hysteresis)
+    pub fn cutoff(&self) -> bool {
+        self.cutoff.get()
+    }
+}
+
+// new sensor temperature (°C), sensor index within config
+pub(crate) fn thermal_update(
+    state: &Rc<EvseState>,
+    idx: usize,
+    celsius: f64,
+) -> Result<(), AfbError> {
+    let thermal = match &state.thermal {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    let sensors = &thermal.config.sensors;
+
+    let (limit, cutoff, derating) = {
+        let mut levels = thermal.levels.borrow_mut();
+        let sensor = &sensors[idx];
+        let level = sensor.level(celsius);
+        if level >= levels[idx] {
+            levels[idx] = level;
+        } else {
+            levels[idx] = sensor.level(celsius + thermal.config.hysteresis).min(levels[idx]);
+        }
+        afb_log_msg!(
+            Debug,
+            None,
+            "{}: sensor:{} {}°C level:{}",
+            state.uid,
+            sensor.uid,
+            celsius,
+            levels[idx]
+        );
+
+        let limit = sensors
+            .iter()
+            .zip(levels.iter())
+            .filter_map(|(sensor, level)| sensor.amps(*level))
+            .min();
+        let cutoff = sensors
+            .iter()
+            .zip(levels.iter())
+            .any(|(sensor, level)| *level > sensor.thresholds.len());
+        (limit, cutoff, levels.iter().any(|level| *level > 0))
+    };
+
+    if derating != thermal.derating.get() {
+        thermal.derating.set(derating);
+        afb_log_msg!(Warning, None, "{}: overtemp derating:{}", state.uid, derating);
+        state.push(Iec6185Msg::Overtemp(derating));
+    }
+    state.set_limit(LimitSource::Thermal, limit)?;
+
+    let latched = thermal.cutoff.replace(cutoff);
+    if cutoff && !latched {
+        afb_log_msg!(Critical, None, "{}: temperature cut-off, power refused", state.uid);
+        state.withdraw_power()?;
+    }
+
+    let (plugged, pause) = {
+        let data = state.data.borrow();
+        (data.plugged, data.pause)
+    };
+    if cutoff && plugged && pause.is_none() {
+        pause_start(state, PauseReason::Overtemperature)?;
+    } else if !cutoff && latched {
+        afb_log_msg!(Notice, None, "{}: temperature back under cut-off", state.uid);
+        match pause {
+            Some(PauseReason::Overtemperature) => {
+                pause_resume(state)?;
+            }
+            _ => state.apply_power()?,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) struct ThermalEvtCtx {
+    pub state: Rc<EvseState>,
+    pub idx: usize,
+    pub key: &'static str,
+}
+
+pub(crate) fn thermal_event_cb(
+    _evt: &AfbEventMsg,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ThermalEvtCtx>()?;
+    let jevent = args.get::<JsoncObj>(0)?;
+    thermal_update(&ctx.state, ctx.idx, jevent.get::<f64>(ctx.key)?)
+}
+
+struct ThermalTimerCtx {
+    state: Rc<EvseState>,
+}
+
+fn thermal_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ThermalTimerCtx>()?;
+    let state = &ctx.state;
+    let thermal = match &state.thermal {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+
+    for (idx, sensor) in thermal.config.sensors.iter().enumerate() {
+        let path = match sensor.source {
+            ThermalSource::Hwmon { path } => path,
+            _ => continue,
+        };
+        let millis = match fs::read_to_string(path) {
+            Ok(value) => value.trim().parse::<f64>().ok(),
+            Err(_) => None,
+        };
+        match millis {
+            Some(value) => thermal_update(state, idx, value / 1000.0)?,
+            None => {
+                // fail safe: a lost sensor may hide an overheating connector
+                afb_log_msg!(Error, None, "{}: sensor:{} unreadable", state.uid, sensor.uid);
+                thermal_update(state, idx, sensor.cutoff)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// register event handlers and start hwmon polling
+pub(crate) fn thermal_start(state: &Rc<EvseState>, api: &mut AfbApi) -> Result<(), AfbError> {
+    let thermal = match &state.thermal {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+
+    let mut hwmon = false;
+    for (idx, sensor) in thermal.config.sensors.iter().enumerate() {
+        match sensor.source {
+            ThermalSource::Event { event, key, .. } => {
+                let name = to_static_str(format!("{}-{}-evt", state.uid, sensor.uid));
+                let handler = AfbEvtHandler::new(name)
+                    .set_pattern(event)
+                    .set_callback(thermal_event_cb)
+                    .set_context(ThermalEvtCtx {
+                        state: state.clone(),
+                        idx,
+                        key,
+                    })
+                    .finalize()?;
+                api.add_evt_handler(handler);
+            }
+            ThermalSource::Hwmon { .. } => hwmon = true,
+            ThermalSource::Firmware => {}
+        }
+    }
+
+    if hwmon && thermal.config.tic > 0 {
+        AfbTimer::new("thermal-tic")
+            .set_period(thermal.config.tic)
+            .set_decount(0)
+            .set_callback(thermal_timer_cb)
+            .set_context(ThermalTimerCtx {
+                state: state.clone(),
+            })
+            .start()?;
+    }
+    Ok(())
+}
@@ -18,7 +18,6 @@
  *  following code is a RUST an API version of Pionix ti-am62x-evse-sdk user space module
  *  interfacing through kernel RPMSG the firmware running in the MCU/M4 cortex.
  */
-use std::cell::Cell;
 use std::mem::MaybeUninit;
 use std::rc::Rc;
 
@@ -32,108 +31,190 @@ const PROTOBUF_MAX_CAPACITY: usize = 256;
 
 // timer ctx and callback
 struct DevTimerCtx {
-    dev: Rc<TiRpmsg>,
+    devs: Vec<Rc<TiRpmsg>>,
     heartbeat: Vec<u8>,
 }
 
 fn timer_callback(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
     let ctx = ctx.get_ref::<DevTimerCtx>()?;
-    // send heartbeat message
-    ctx.dev.write(&ctx.heartbeat)
+    // send heartbeat message, one failing endpoint should not starve the others
+    let mut status = Ok(());
+    for dev in &ctx.devs {
+        if let Err(error) = dev.write(&ctx.heartbeat) {
+            afb_log_msg!(Error, None, "heartbeat write fail:{}", error);
+            status = Err(error);
+        }
+    }
+    status
 }
 
-fn process_iec6185(
-    apiv4: AfbApiV4,
-    iec: &Iec61851Event,
-    ctx: &mut DevAsyncCtx,
+pub(crate) fn heartbeat_start(
+    uid: &'static str,
+    tic: u32,
+    devs: Vec<Rc<TiRpmsg>>,
 ) -> Result<(), AfbError> {
-    let action_on = JsoncObj::new();
-    action_on.add("action", "on").unwrap();
-    let action_off = JsoncObj::new();
-    action_off.add("action", "off").unwrap();
+    AfbTimer::new(uid)
+        .set_period(tic)
+        .set_decount(0)
+        .set_callback(timer_callback)
+        .set_context(DevTimerCtx {
+            heartbeat: mk_heartbeat()?,
+            devs,
+        })
+        .start()?;
+    Ok(())
+}
 
+fn process_iec6185(iec: &Iec61851Event, ctx: &mut DevAsyncCtx) -> Result<(), AfbError> {
     let iec_msg = match iec {
         Iec61851Event::CarPluggedIn => {
-            AfbSubCall::call_sync(apiv4, ctx.lock_api, ctx.lock_verb, action_on)?;
+            if replug_running(&ctx.state) {
+                afb_log_msg!(Debug, None, "Replug muted:{:?}", iec);
+                ctx.state.data.borrow_mut().replug_plugged = true;
+                return Ok(());
+            }
+            {
+                let mut data = ctx.state.data.borrow_mut();
+                data.plugged = true;
+                data.wakeup_tried = false;
+                data.ev_done = false;
+            }
+            session_start(&ctx.state);
+            budget_update(&ctx.state)?;
+            lock_trigger(&ctx.state, LockTrigger::Plug)?;
+            // random delay starts with the authorization grant
+            auth_plugged(&ctx.state)?;
+            wakeup_arm(&ctx.state)?;
             Iec6185Msg::Plugged(true)
         }
 
         Iec61851Event::CarUnplugged => {
-            let msg = mk_pwm(&PwmState::Off, 0.0)?;
-            ctx.dev.write(&msg)?;
-            // Fulup TBD for test only unlock motor as soon as IEC-UNLOCK is received
-            AfbSubCall::call_sync(apiv4, ctx.lock_api, ctx.lock_verb, action_off)?;
-            Iec6185Msg::Plugged(false)
+            if replug_running(&ctx.state) {
+                afb_log_msg!(Debug, None, "Replug muted:{:?}", iec);
+                ctx.state.data.borrow_mut().replug_plugged = false;
+                return Ok(());
+            }
+            return plug_removed(&ctx.state);
         }
 
         Iec61851Event::CarRequestedPower => {
-            // send request to charging manager authorization
-            AfbSubCall::call_sync(apiv4, ctx.lock_api, ctx.lock_verb, action_on)?;
+            let resumed = {
+                let mut data = ctx.state.data.borrow_mut();
+                data.power_rqt = true;
+                let resumed = data.ev_done;
+                data.ev_done = false;
+                resumed
+            };
+            if resumed {
+                budget_update(&ctx.state)?;
+            }
+            wakeup_check(&ctx.state);
+            lock_trigger(&ctx.state, LockTrigger::Power)?;
+            // wait for session authorization before allowing power
+            auth_power_rqt(&ctx.state)?;
             Iec6185Msg::PowerRqt(true)
         }
 
         Iec61851Event::CarRequestedStopPower => {
-            // set max power 0
-            // M4 firmware cut power
-            ctx.imax = 0;
-            AfbSubCall::call_sync(apiv4, ctx.lock_api, ctx.lock_verb, action_off)?;
+            // M4 firmware cut power, cable rating (imax) stays valid until unplug
+            ctx.state.data.borrow_mut().power_rqt = false;
+            let reason = match ctx.state.data.borrow().pause {
+                Some(_) => StopReason::Paused,
+                None => StopReason::Ev,
+            };
+            session_stop_reason(&ctx.state, reason);
+            // car is done, give its share to the others
+            if reason == StopReason::Ev {
+                ctx.state.data.borrow_mut().ev_done = true;
+                budget_update(&ctx.state)?;
+            }
+            // car acknowledged a pending pause
+            pause_stopped(&ctx.state)?;
+            lock_trigger(&ctx.state, LockTrigger::Stop)?;
             Iec6185Msg::PowerRqt(false)
         }
 
         // relay close vehicle charging
         Iec61851Event::PowerOn => {
+            ctx.state.data.borrow_mut().relay_on = true;
+            session_relay(&ctx.state, true);
             // notify max current
             Iec6185Msg::RelayOn(true)
         }
 
         // relay close vehicle charging
         Iec61851Event::PowerOff => {
-            // unlock motor
-            AfbSubCall::call_sync(apiv4, ctx.lock_api, ctx.lock_verb, action_off)?;
+            ctx.state.data.borrow_mut().relay_on = false;
+            session_relay(&ctx.state, false);
+            lock_trigger(&ctx.state, LockTrigger::RelayOff)?;
+            lock_relay_open(&ctx.state)?;
+            phases_relay_open(&ctx.state)?;
             Iec6185Msg::RelayOn(false)
         }
 
+        Iec61851Event::EvseReplugStarted => {
+            afb_log_msg!(Debug, None, "New iec6185:{:?}", iec);
+            return Ok(());
+        }
+
+        Iec61851Event::EvseReplugFinished => {
+            return replug_done(&ctx.state);
+        }
+
         Iec61851Event::ErrorE
         | Iec61851Event::ErrorDf
         | Iec61851Event::ErrorRelais
         | Iec61851Event::ErrorRcd => {
-            // no action send error message
+            ctx.state.fault_set(iec.as_str_name());
+            session_fault(&ctx.state, iec.as_str_name());
+            store_fault(&ctx.state, iec.as_str_name());
+            lock_trigger(&ctx.state, LockTrigger::Fault)?;
+            Iec6185Msg::Error(iec.as_str_name().to_string())
+        }
+
+        // latched fault, release cable as soon as relay is open
+        Iec61851Event::PermanentFault => {
+            ctx.state.fault_set(iec.as_str_name());
+            session_fault(&ctx.state, iec.as_str_name());
+            store_fault(&ctx.state, iec.as_str_name());
+            lock_trigger(&ctx.state, LockTrigger::Fault)?;
+            lock_auto_unlock(&ctx.state)?;
             Iec6185Msg::Error(iec.as_str_name().to_string())
         }
 
         Iec61851Event::PpImax13a => {
-            if ctx.imax == 13 {
+            if !ctx.state.set_imax(13) {
                 return Ok(());
             }
             afb_log_msg!(Debug, None, "New iec6185:{:?}", iec);
-            ctx.imax = 13;
-            Iec6185Msg::CableImax(ctx.imax)
+            budget_update(&ctx.state)?;
+            Iec6185Msg::CableImax(13)
         }
         Iec61851Event::PpImax20a => {
-            if ctx.imax == 20 {
+            if !ctx.state.set_imax(20) {
                 return Ok(());
             }
             afb_log_msg!(Debug, None, "New iec6185:{:?}", iec);
-            ctx.imax = 20;
-            Iec6185Msg::CableImax(ctx.imax)
+            budget_update(&ctx.state)?;
+            Iec6185Msg::CableImax(20)
         }
 
         Iec61851Event::PpImax32a => {
-            if ctx.imax == 32 {
+            if !ctx.state.set_imax(32) {
                 return Ok(());
             }
             afb_log_msg!(Debug, None, "New iec6185:{:?}", iec);
-            ctx.imax = 32;
-            Iec6185Msg::CableImax(ctx.imax)
+            budget_update(&ctx.state)?;
+            Iec6185Msg::CableImax(32)
         }
 
         Iec61851Event::PpImax64a => {
-            if ctx.imax == 32 {
+            if !ctx.state.set_imax(64) {
                 return Ok(());
             }
             afb_log_msg!(Debug, None, "New iec6185:{:?}", iec);
-            ctx.imax = 64;
-            Iec6185Msg::CableImax(ctx.imax)
+            budget_update(&ctx.state)?;
+            Iec6185Msg::CableImax(64)
         }
 
         _ => {
@@ -143,20 +224,40 @@ fn process_iec6185(
         }
     };
 
-    afb_log_msg!(Notice, None, "JobPost push event:{:?}", iec_msg);
-    ctx.evt.push(iec_msg);
+    ctx.state.push(iec_msg);
+    Ok(())
+}
+
+// car left (firmware event or unplug seen during a replug sequence)
+pub(crate) fn plug_removed(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    {
+        let mut data = state.data.borrow_mut();
+        data.plugged = false;
+        data.power_rqt = false;
+    }
+    state.faults_clear();
+    pause_unplugged(state);
+    phases_abort(state);
+    state.pwm_off()?;
+    delay_unplugged(state);
+    surplus_reset(state)?;
+    budget_update(state)?;
+    lock_trigger(state, LockTrigger::Unplug)?;
+    lock_trigger(state, LockTrigger::SessionEnd)?;
+    lock_auto_unlock(state)?;
+    auth_unplugged(state)?;
+    if lock_unplug_pending(state) {
+        state.data.borrow_mut().unplug_pending = true;
+        return Ok(());
+    }
+    state.push(Iec6185Msg::Plugged(false));
+    session_end(state);
     Ok(())
 }
 
 // on event ctx and callback
 struct DevAsyncCtx {
-    count: Cell<u32>,
-    dev: Rc<TiRpmsg>,
-    lock_api: &'static str,
-    lock_verb: &'static str,
-    imax: u32,
-    evt: &'static AfbEvent,
-    apiv4: AfbApiV4,
+    state: Rc<EvseState>,
 }
 
 fn async_dev_cb(_event: &AfbEvtFd, revent: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
@@ -166,20 +267,39 @@ fn async_dev_cb(_event: &AfbEvtFd, revent: u32, ctx: &AfbCtxData) -> Result<(),
         let mut buffer: [u8; PROTOBUF_MAX_CAPACITY as usize] =
             unsafe { MaybeUninit::uninit().assume_init() };
 
-        let len = ctx.dev.read(&mut buffer)?;
+        let len = ctx.state.dev.read(&mut buffer)?;
         let data = &buffer[0..len];
-        match msg_uncode(data) {
+        let msg = msg_uncode(data);
+        ctx.state.history.firmware(&msg);
+        match msg {
             EventMsg::Err(error) => {
                 afb_log_msg!(Critical, None, "{}", error);
             }
 
             EventMsg::Heartbeat() => {
-                let count = ctx.count.get() + 1;
-                ctx.count.set(count);
+                ctx.state.heartbeat();
             }
 
             EventMsg::Evt(iec6185) => {
-                process_iec6185(ctx.apiv4, &iec6185, &mut ctx)?;
+                // events published while processing carry firmware event name
+                ctx.state.evt_iec.set(Some(iec6185.as_str_name()));
+                let status = process_iec6185(&iec6185, &mut ctx);
+                ctx.state.evt_iec.set(None);
+                status?;
+            }
+
+            EventMsg::Phases(count) => {
+                phases_confirm(&ctx.state, count)?;
+            }
+
+            EventMsg::Lock(locked) => {
+                ctx.state.lock.backend.notify(locked);
+            }
+
+            EventMsg::Version(version) => {
+                afb_log_msg!(Notice, None, "{}: firmware version:{}", ctx.state.uid, version);
+                ctx.state.data.borrow_mut().firmware = Some(version.clone());
+                ctx.state.push(Iec6185Msg::Firmware(version));
             }
         }
     }
@@ -187,7 +307,7 @@ fn async_dev_cb(_event: &AfbEvtFd, revent: u32, ctx: &AfbCtxData) -> Result<(),
 }
 
 struct SubscribeData {
-    evt: &'static AfbEvent,
+    state: Rc<EvseState>,
 }
 
 fn subscribe_callback(
@@ -196,14 +316,34 @@ fn subscribe_callback(
     ctx: &AfbCtxData,
 ) -> Result<(), AfbError> {
     let ctx = ctx.get_ref::<SubscribeData>()?;
-    let subcription = args.get::<bool>(0)?;
 
+    // true|false: legacy 'iec' event, {'events':[...]} selected channels only
+    let (subcription, events) = match args.get::<bool>(0) {
+        Ok(value) => (value, vec![ctx.state.evt]),
+        Err(_) => {
+            let query = args.get::<JsoncObj>(0)?;
+            let jevents = query.get::<JsoncObj>("events")?;
+            let mut events = Vec::new();
+            for idx in 0..jevents.count()? {
+                let channel = EvseChannel::parse(jevents.index::<&str>(idx)?)?;
+                events.push(ctx.state.channels.get(channel));
+            }
+            (query.default::<bool>("subscribe", true)?, events)
+        }
+    };
+
+    // new subscriber receives current state snapshot within reply
     if subcription {
-        ctx.evt.subscribe(request)?;
+        for event in events {
+            event.subscribe(request)?;
+        }
+        request.reply(ctx.state.status(), 0);
     } else {
-        ctx.evt.unsubscribe(request)?;
+        for event in events {
+            event.unsubscribe(request)?;
+        }
+        request.reply(AFB_NO_DATA, 0);
     }
-    request.reply(AFB_NO_DATA, 0);
     Ok(())
 }
 
@@ -231,9 +371,7 @@ fn enable_callback(
 }
 
 struct PowerData {
-    dev: Rc<TiRpmsg>,
-    enable: Vec<u8>,
-    disable: Vec<u8>,
+    state: Rc<EvseState>,
 }
 
 fn power_callback(
@@ -244,8 +382,7 @@ fn power_callback(
     let ctx = ctx.get_ref::<PowerData>()?;
     let enable = args.get::<bool>(0)?;
 
-    let msg = if enable { &ctx.enable } else { &ctx.disable };
-    if let Err(error) = ctx.dev.write(msg) {
+    if let Err(error) = ctx.state.set_power(enable) {
         return afb_error!("m4-rpc-fail", "power({}):{}", enable, error);
     };
 
@@ -254,7 +391,7 @@ fn power_callback(
 }
 
 struct SetPwmData {
-    dev: Rc<TiRpmsg>,
+    state: Rc<EvseState>,
 }
 
 fn setpwm_callback(
@@ -277,17 +414,16 @@ fn setpwm_callback(
         Err(_) => 0.0,
     };
 
-    // this message cannot be build statically
-    let msg = mk_pwm(&state, duty)?;
-    if let Err(error) = ctx.dev.write(&msg) {
+    if let Err(error) = ctx.state.set_pwm(state, duty) {
         return afb_error!("m4-rpc-fail", "set_pwm({:?}):{}", state, error);
     };
+    wakeup_arm(&ctx.state)?;
     request.reply(AFB_NO_DATA, 0);
     Ok(())
 }
 
 struct SetImaxData {
-    dev: Rc<TiRpmsg>,
+    state: Rc<EvseState>,
 }
 
 fn set_imax_callback(
@@ -296,20 +432,18 @@ fn set_imax_callback(
     ctx: &AfbCtxData,
 ) -> Result<(), AfbError> {
     let ctx = ctx.get_ref::<SetImaxData>()?;
-    let imax = args.get::<u32>(0)?;
-    let duty = imax as f32 / 60.0;
+    let imax = args.get::<u32>(0)?.min(ctx.state.max_current);
 
-    // this message cannot be build statically
-    let msg = mk_pwm(&PwmState::On, duty)?;
-    if let Err(error) = ctx.dev.write(&msg) {
+    if let Err(error) = ctx.state.set_pwm(PwmState::On, imax_to_duty(imax)) {
         return afb_error!("m4-rpc-fail", "set_imax({}) {}", imax, error);
     };
+    wakeup_arm(&ctx.state)?;
     request.reply(AFB_NO_DATA, 0);
     Ok(())
 }
 
 struct SetSlacData {
-    dev: Rc<TiRpmsg>,
+    state: Rc<EvseState>,
 }
 
 fn setslac_callback(
@@ -330,19 +464,432 @@ fn setslac_callback(
 
     // this message cannot be build statically
     let msg = mk_slac(&state)?;
-    if let Err(error) = ctx.dev.write(&msg) {
+    if let Err(error) = ctx.state.dev.write(&msg) {
         return afb_error!("m4-rpc-fail", "set_slac({:?}):{}", state, error);
     };
+    ctx.state.data.borrow_mut().slac = state;
+    ctx.state.push(Iec6185Msg::Slac(state.as_str_name().to_lowercase()));
+
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+struct PauseData {
+    state: Rc<EvseState>,
+}
+
+fn pause_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PauseData>()?;
+
+    let reason = match args.get::<JsoncObj>(0) {
+        Ok(query) => match query.optional::<String>("reason")? {
+            None => PauseReason::Operator,
+            Some(value) => match value.to_uppercase().as_str() {
+                "SCHEDULER" => PauseReason::Scheduler,
+                "OPERATOR" => PauseReason::Operator,
+                "OVERTEMPERATURE" => PauseReason::Overtemperature,
+                _ => {
+                    return afb_error!(
+                        "pause-invalid-query",
+                        "reason should be scheduler|operator|overtemperature"
+                    )
+                }
+            },
+        },
+        Err(_) => PauseReason::Operator,
+    };
+
+    pause_start(&ctx.state, reason)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+fn resume_callback(
+    request: &AfbRequest,
+    _args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PauseData>()?;
+    match ctx.state.pause_held() {
+        Some(PauseReason::Overtemperature) => {
+            return afb_error!("pause-overtemp", "connector above temperature cut-off")
+        }
+        Some(PauseReason::Budget) => {
+            return afb_error!("pause-budget", "site budget cannot grant minimum current")
+        }
+        _ => {}
+    }
+    pause_resume(&ctx.state)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+struct SequenceData {
+    state: Rc<EvseState>,
+}
+
+fn wakeup_callback(
+    request: &AfbRequest,
+    _args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<SequenceData>()?;
+    wakeup_start(&ctx.state)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+fn replug_callback(
+    request: &AfbRequest,
+    _args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<SequenceData>()?;
+    replug_start(&ctx.state)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+struct PhasesData {
+    state: Rc<EvseState>,
+}
+
+fn phases_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<PhasesData>()?;
+    let count = args.get::<u32>(0)?;
+
+    phases_start(&ctx.state, count)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+struct UnlockData {
+    state: Rc<EvseState>,
+}
+
+fn unlock_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<UnlockData>()?;
+    let force = match args.get::<JsoncObj>(0) {
+        Ok(query) => query.default::<bool>("force", false)?,
+        Err(_) => false,
+    };
+
+    if force {
+        let data = ctx.state.data.borrow();
+        afb_log_msg!(
+            Critical,
+            request,
+            "AUDIT {}: forced unlock relay_on:{} pwm:{:?} pause:{:?}",
+            ctx.state.uid,
+            data.relay_on,
+            data.pwm_state,
+            data.pause
+        );
+    }
+
+    lock_release(&ctx.state, force)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+struct AuthData {
+    state: Rc<EvseState>,
+}
+
+fn auth_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<AuthData>()?;
+    let query = args.get::<JsoncObj>(0)?;
 
+    if query.default::<bool>("revoke", false)? {
+        auth_set(&ctx.state, false)?;
+    } else {
+        auth_tag(&ctx.state, query.get::<&str>("tag")?)?;
+    }
     request.reply(AFB_NO_DATA, 0);
     Ok(())
 }
 
+struct SessionCtx {
+    state: Rc<EvseState>,
+}
+
+fn session_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<SessionCtx>()?;
+    let last = match args.get::<JsoncObj>(0) {
+        Ok(query) => query.default::<bool>("last", false)?,
+        Err(_) => false,
+    };
+
+    let session = {
+        let data = ctx.state.data.borrow();
+        if last {
+            data.session.last.clone()
+        } else {
+            data.session.current.clone()
+        }
+    };
+    match session {
+        Some(session) => request.reply(session, 0),
+        None => {
+            return afb_error!(
+                "session-not-found",
+                "no {} session",
+                if last { "closed" } else { "running" }
+            )
+        }
+    }
+    Ok(())
+}
+
+struct LogCtx {
+    state: Rc<EvseState>,
+}
+
+fn log_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<LogCtx>()?;
+    let store = match &ctx.state.store {
+        Some(value) => value,
+        None => return afb_error!("log-not-configured", "no store path in binding config"),
+    };
+
+    let query = match args.get::<JsoncObj>(0) {
+        Ok(value) => value,
+        Err(_) => JsoncObj::new(),
+    };
+    let kind = query.optional::<&str>("kind")?;
+    if let Some(value) = kind {
+        if value != "session" && value != "fault" {
+            return afb_error!("log-invalid-query", "kind should be session|fault");
+        }
+    }
+
+    let (total, entries) = store.query(&StoreQuery {
+        from: query.default::<u64>("from", 0)?,
+        to: query.default::<u64>("to", u64::MAX)?,
+        kind,
+        offset: query.default::<u32>("offset", 0)? as usize,
+        limit: query.default::<u32>("limit", 50)? as usize,
+    });
+
+    let reply = JsoncObj::parse(&format!("{{\"total\":{},\"entries\":{}}}", total, entries))?;
+    request.reply(reply, 0);
+    Ok(())
+}
+
+struct LimitCtx {
+    state: Rc<EvseState>,
+}
+
+fn limit_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<LimitCtx>()?;
+    let query = args.get::<JsoncObj>(0)?;
+
+    let amps = if query.default::<bool>("clear", false)? {
+        None
+    } else {
+        let amps = query.get::<u32>("amps")?;
+        if amps < IEC_MIN_CURRENT || amps > ctx.state.max_current {
+            return afb_error!(
+                "ext-limit-invalid",
+                "amps:{} should be within [{},{}]",
+                amps,
+                IEC_MIN_CURRENT,
+                ctx.state.max_current
+            );
+        }
+        Some(amps)
+    };
+    ext_limit_set(&ctx.state, amps, query.default::<u32>("duration_s", 0)?)?;
+    request.reply(AFB_NO_DATA, 0);
+    Ok(())
+}
+
+struct ProfileVerbCtx {
+    state: Rc<EvseState>,
+}
+
+fn profile_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ProfileVerbCtx>()?;
+    let query = match args.get::<JsoncObj>(0) {
+        Ok(value) => value,
+        Err(_) => JsoncObj::new(),
+    };
+
+    match query.default::<&str>("action", "get")?.to_uppercase().as_str() {
+        "SET" => {
+            let profile = ChargingProfile::from_jsonc(&query.get::<JsoncObj>("profile")?)?;
+            ctx.state.profile.set(profile);
+            profile_apply(&ctx.state)?;
+            request.reply(AFB_NO_DATA, 0);
+        }
+        "CLEAR" => {
+            let count = ctx.state.profile.clear(query.optional::<u32>("id")?);
+            profile_apply(&ctx.state)?;
+            request.reply(count as u32, 0);
+        }
+        "GET" => {
+            let schedule = ctx.state.profile.to_json(
+                now_ms() / 1000,
+                query.default::<u64>("duration", 86400)?,
+            )?;
+            request.reply(JsoncObj::parse(&schedule)?, 0);
+        }
+        _ => return afb_error!("profile-invalid-query", "action should be set|clear|get"),
+    }
+    Ok(())
+}
+
+struct DelayVerbCtx {
+    state: Rc<EvseState>,
+}
+
+// operator override, reply remaining delay in ms (0 when none)
+fn delay_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<DelayVerbCtx>()?;
+    let query = match args.get::<JsoncObj>(0) {
+        Ok(value) => value,
+        Err(_) => JsoncObj::new(),
+    };
+
+    if query.default::<bool>("skip", false)? {
+        afb_log_msg!(Notice, None, "{}: random delay skipped", ctx.state.uid);
+        delay_cancel(&ctx.state)?;
+    }
+    let reply = JsoncObj::new();
+    reply.add("remaining", ctx.state.delay.remaining().unwrap_or(0) as u32)?;
+    request.reply(reply, 0);
+    Ok(())
+}
+
+struct SurplusVerbCtx {
+    state: Rc<EvseState>,
+}
+
+// enable/disable surplus mode, 'grid' injects a meter reading (stand-in meter)
+fn surplus_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<SurplusVerbCtx>()?;
+    let state = &ctx.state;
+    let query = match args.get::<JsoncObj>(0) {
+        Ok(value) => value,
+        Err(_) => JsoncObj::new(),
+    };
+
+    if let Some(enable) = query.optional::<bool>("enable")? {
+        surplus_enable(state, enable)?;
+    }
+    if let Some(grid) = query.optional::<i32>("grid")? {
+        surplus_update(state, grid)?;
+    }
+
+    let reply = JsoncObj::new();
+    reply.add("enabled", state.surplus.enabled.get())?;
+    if let Some(grid) = state.surplus.grid.get() {
+        reply.add("grid", grid)?;
+    }
+    if let Some(target) = state.surplus.target.get() {
+        reply.add("target", target)?;
+    }
+    request.reply(reply, 0);
+    Ok(())
+}
+
+struct HistoryCtx {
+    state: Rc<EvseState>,
+}
+
+fn history_callback(
+    request: &AfbRequest,
+    args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<HistoryCtx>()?;
+    let query = match args.get::<JsoncObj>(0) {
+        Ok(value) => value,
+        Err(_) => JsoncObj::new(),
+    };
+
+    let kind = match query.optional::<&str>("kind")? {
+        None => None,
+        Some(value) => match value.to_uppercase().as_str() {
+            "FIRMWARE" => Some(HistoryKind::Firmware),
+            "PUBLISHED" => Some(HistoryKind::Published),
+            _ => return afb_error!("history-invalid-query", "kind should be firmware|published"),
+        },
+    };
+    let channel = match query.optional::<&str>("channel")? {
+        Some(value) => Some(EvseChannel::parse(value)?.name()),
+        None => None,
+    };
+
+    let entries = ctx.state.history.query(&HistoryQuery {
+        kind,
+        channel,
+        from: query.default::<u64>("from", 0)?,
+        to: query.default::<u64>("to", u64::MAX)?,
+    })?;
+    request.reply(JsoncObj::parse(&entries)?, 0);
+    Ok(())
+}
+
+struct StatusCtx {
+    state: Rc<EvseState>,
+}
+
+fn status_callback(
+    request: &AfbRequest,
+    _args: &AfbRqtData,
+    ctx: &AfbCtxData,
+) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<StatusCtx>()?;
+    request.reply(ctx.state.status(), 0);
+    Ok(())
+}
+
 pub(crate) fn register(
     rootv4: AfbApiV4,
     api: &mut AfbApi,
     config: &ApiUserData,
-) -> Result<(), AfbError> {
+) -> Result<Rc<EvseState>, AfbError> {
     let ti_dev = TiRpmsg::new(config.cdev, config.rport, config.eptname)?;
     let handle = Rc::new(ti_dev);
 
@@ -355,7 +902,8 @@ pub(crate) fn register(
     handle.write(&msg)?;
 
     // create event and store it within callback context
-    let event = AfbEvent::new("iec");
+    let event = AfbEvent::new(config.event);
+    let state = EvseState::new(rootv4, handle.clone(), event, config);
 
     // register dev handler within listening event loop
     AfbEvtFd::new(config.uid)
@@ -363,34 +911,18 @@ pub(crate) fn register(
         .set_events(AfbEvtFdPoll::IN)
         .set_callback(async_dev_cb)
         .set_context(DevAsyncCtx {
-            apiv4: rootv4,
-            evt: event,
-            count: Cell::new(0),
-            dev: handle.clone(),
-            lock_api: config.lock_api,
-            lock_verb: config.lock_verb,
-            imax: 0,
+            state: state.clone(),
         })
         .start()?;
 
-    // set heartbeat timer
-    if config.tic > 0 {
-        AfbTimer::new(config.uid)
-            .set_period(config.tic)
-            .set_decount(0)
-            .set_callback(timer_callback)
-            .set_context(DevTimerCtx {
-                heartbeat: mk_heartbeat()?,
-                dev: handle.clone(),
-            })
-            .start()?;
-    }
-
-    let subscribe = AfbVerb::new("subscribe")
+    let subscribe = AfbVerb::new(prefixed_name(config.prefix, "subscribe"))
         .set_callback(subscribe_callback)
-        .set_context(SubscribeData { evt: event })
-        .set_info("subscribe Iec6185 event")
-        .set_usage("true|false")
+        .set_context(SubscribeData {
+            state: state.clone(),
+        })
+        .set_info("subscribe iec or plug|power|cable|relay|error|slac|mcu|limit|session|ocpp events")
+        .set_usage("true|false|{'events':['xxx'],'subscribe':true}")
+        .add_sample("{'events':['error','relay']}")?
         .finalize()?;
 
     let ctx = EnableData {
@@ -399,7 +931,7 @@ pub(crate) fn register(
         disable: mk_disable()?,
     };
 
-    let dev_enable = AfbVerb::new("iec6185")
+    let dev_enable = AfbVerb::new(prefixed_name(config.prefix, "iec6185"))
         .set_callback(enable_callback)
         .set_context(ctx)
         .set_info("enable/disable Iec6185 event (true/false)")
@@ -407,10 +939,10 @@ pub(crate) fn register(
         .finalize()?;
 
     let ctx = SetPwmData {
-        dev: handle.clone(),
+        state: state.clone(),
     };
 
-    let set_pwm = AfbVerb::new("pwm")
+    let set_pwm = AfbVerb::new(prefixed_name(config.prefix, "pwm"))
         .set_callback(setpwm_callback)
         .set_context(ctx)
         .set_info("set_pwm")
@@ -420,10 +952,10 @@ pub(crate) fn register(
         .finalize()?;
 
     let ctx = SetImaxData {
-        dev: handle.clone(),
+        state: state.clone(),
     };
 
-    let set_imax = AfbVerb::new("imax")
+    let set_imax = AfbVerb::new(prefixed_name(config.prefix, "imax"))
         .set_callback(set_imax_callback)
         .set_context(ctx)
         .set_info("set_pwm")
@@ -431,9 +963,9 @@ pub(crate) fn register(
         .finalize()?;
 
     let ctx = SetSlacData {
-        dev: handle.clone(),
+        state: state.clone(),
     };
-    let slac_status = AfbVerb::new("slac")
+    let slac_status = AfbVerb::new(prefixed_name(config.prefix, "slac"))
         .set_callback(setslac_callback)
         .set_context(ctx)
         .set_info("set slac status")
@@ -443,24 +975,232 @@ pub(crate) fn register(
         .finalize()?;
 
     let ctx = PowerData {
-        dev: handle.clone(),
-        enable: mk_power(true)?,
-        disable: mk_power(false)?,
+        state: state.clone(),
     };
-    let allow_power = AfbVerb::new("power")
+    let allow_power = AfbVerb::new(prefixed_name(config.prefix, "power"))
         .set_callback(power_callback)
         .set_context(ctx)
         .set_info("allow power (true/false)")
         .set_usage("true/false")
         .finalize()?;
 
+    let pause = AfbVerb::new(prefixed_name(config.prefix, "pause"))
+        .set_callback(pause_callback)
+        .set_context(PauseData {
+            state: state.clone(),
+        })
+        .set_info("pause charging without closing session")
+        .set_usage("{'reason':'scheduler|operator|overtemperature'}")
+        .add_sample("{'reason':'operator'}")?
+        .finalize()?;
+
+    let resume = AfbVerb::new(prefixed_name(config.prefix, "resume"))
+        .set_callback(resume_callback)
+        .set_context(PauseData {
+            state: state.clone(),
+        })
+        .set_info("resume paused charging")
+        .set_usage("no input")
+        .finalize()?;
+
+    let wakeup = AfbVerb::new(prefixed_name(config.prefix, "wakeup"))
+        .set_callback(wakeup_callback)
+        .set_context(SequenceData {
+            state: state.clone(),
+        })
+        .set_info("run IEC61851 A.5.3 EV wake-up sequence")
+        .set_usage("no input")
+        .finalize()?;
+
+    let replug = AfbVerb::new(prefixed_name(config.prefix, "replug"))
+        .set_callback(replug_callback)
+        .set_context(SequenceData {
+            state: state.clone(),
+        })
+        .set_info("run a virtual unplug/replug sequence")
+        .set_usage("no input")
+        .finalize()?;
+
+    let phases = AfbVerb::new(prefixed_name(config.prefix, "phases"))
+        .set_callback(phases_callback)
+        .set_context(PhasesData {
+            state: state.clone(),
+        })
+        .set_info("switch charging phase count")
+        .set_usage("1|3")
+        .finalize()?;
+
+    let unlock = AfbVerb::new(prefixed_name(config.prefix, "unlock"))
+        .set_callback(unlock_callback)
+        .set_context(UnlockData {
+            state: state.clone(),
+        })
+        .set_info("operator/emergency cable unlock (relay and pwm must be off)")
+        .set_usage("{'force':false}")
+        .add_sample("{'force':true}")?;
+    if let Some(permission) = config.admin_permission {
+        unlock.set_permission(AfbPermission::new(permission));
+    }
+    let unlock = unlock.finalize()?;
+
+    let auth = AfbVerb::new(prefixed_name(config.prefix, "auth"))
+        .set_callback(auth_callback)
+        .set_context(AuthData {
+            state: state.clone(),
+        })
+        .set_info("authorize session from local allow-list or revoke it")
+        .set_usage("{'tag':'xxx'}|{'revoke':true}");
+    if let Some(permission) = config.admin_permission {
+        auth.set_permission(AfbPermission::new(permission));
+    }
+    let auth = auth.finalize()?;
+
+    let session = AfbVerb::new(prefixed_name(config.prefix, "session"))
+        .set_callback(session_callback)
+        .set_context(SessionCtx {
+            state: state.clone(),
+        })
+        .set_info("running charging session or last closed one")
+        .set_usage("{'last':false}")
+        .add_sample("{'last':true}")?
+        .finalize()?;
+
+    let status = AfbVerb::new(prefixed_name(config.prefix, "status"))
+        .set_callback(status_callback)
+        .set_context(StatusCtx {
+            state: state.clone(),
+        })
+        .set_info("current connector state snapshot")
+        .set_usage("no input")
+        .finalize()?;
+
+    let limit = AfbVerb::new(prefixed_name(config.prefix, "limit"))
+        .set_callback(limit_callback)
+        .set_context(LimitCtx {
+            state: state.clone(),
+        })
+        .set_info("external current limit, refresh before duration/keep-alive timeout")
+        .set_usage("{'amps':16,'duration_s':60}|{'clear':true}")
+        .add_sample("{'amps':10,'duration_s':900}")?
+        .finalize()?;
+
+    let profile = AfbVerb::new(prefixed_name(config.prefix, "profile"))
+        .set_callback(profile_callback)
+        .set_context(ProfileVerbCtx {
+            state: state.clone(),
+        })
+        .set_info("set, clear or get charging profiles and composite schedule")
+        .set_usage("{'action':'set|clear|get','profile':{...},'id':1,'duration':86400}")
+        .set_actions("['set','clear','get']")?
+        .add_sample("{'action':'set','profile':{'id':1,'kind':'daily','periods':[{'start':0,'limit':16}]}}")?
+        .finalize()?;
+
+    let delay = AfbVerb::new(prefixed_name(config.prefix, "delay"))
+        .set_callback(delay_callback)
+        .set_context(DelayVerbCtx {
+            state: state.clone(),
+        })
+        .set_info("remaining random delay, skip applies held power/limits now")
+        .set_usage("{'skip':false}")
+        .add_sample("{'skip':true}")?;
+    if let Some(permission) = config.admin_permission {
+        delay.set_permission(AfbPermission::new(permission));
+    }
+    let delay = delay.finalize()?;
+
+    let surplus = AfbVerb::new(prefixed_name(config.prefix, "surplus"))
+        .set_callback(surplus_callback)
+        .set_context(SurplusVerbCtx {
+            state: state.clone(),
+        })
+        .set_info("pv surplus charging mode (grid W: import > 0, export < 0)")
+        .set_usage("{'enable':true,'grid':-3000}")
+        .add_sample("{'enable':true}")?;
+    if let Some(permission) = config.admin_permission {
+        surplus.set_permission(AfbPermission::new(permission));
+    }
+    let surplus = surplus.finalize()?;
+
+    let history = AfbVerb::new(prefixed_name(config.prefix, "history"))
+        .set_callback(history_callback)
+        .set_context(HistoryCtx {
+            state: state.clone(),
+        })
+        .set_info("recent firmware and published events (ts in ms since epoch)")
+        .set_usage("{'kind':'firmware|published','channel':'xxx','from':0,'to':0}")
+        .add_sample("{'kind':'firmware'}")?
+        .finalize()?;
+
+    let log = AfbVerb::new(prefixed_name(config.prefix, "log"))
+        .set_callback(log_callback)
+        .set_context(LogCtx {
+            state: state.clone(),
+        })
+        .set_info("query stored sessions and faults (ts in ms since epoch)")
+        .set_usage("{'from':0,'to':0,'kind':'session|fault','offset':0,'limit':50}")
+        .add_sample("{'kind':'fault','limit':10}")?
+        .finalize()?;
+
+    if state.auth.mode == AuthMode::Event {
+        let handler = AfbEvtHandler::new(prefixed_name(config.prefix, "auth-evt"))
+            .set_pattern(state.auth.event)
+            .set_callback(auth_event_cb)
+            .set_context(AuthEvtCtx {
+                state: state.clone(),
+            })
+            .finalize()?;
+        api.add_evt_handler(handler);
+    }
+
+    if let Some(meter) = &state.meter {
+        let handler = AfbEvtHandler::new(prefixed_name(config.prefix, "meter-current-evt"))
+            .set_pattern(meter.config.event)
+            .set_callback(meter_event_cb)
+            .set_context(MeterEvtCtx {
+                state: state.clone(),
+            })
+            .finalize()?;
+        api.add_evt_handler(handler);
+    }
+
+    thermal_start(&state, api)?;
+
+    if state.surplus.config.required_api().is_some() {
+        let handler = AfbEvtHandler::new(prefixed_name(config.prefix, "meter-evt"))
+            .set_pattern(state.surplus.config.event)
+            .set_callback(surplus_event_cb)
+            .set_context(SurplusEvtCtx {
+                state: state.clone(),
+            })
+            .finalize()?;
+        api.add_evt_handler(handler);
+    }
+
     api.add_event(event);
+    for channel in state.channels.events() {
+        api.add_event(*channel);
+    }
     api.add_verb(subscribe);
     api.add_verb(set_pwm);
     api.add_verb(set_imax);
     api.add_verb(dev_enable);
     api.add_verb(allow_power);
     api.add_verb(slac_status);
+    api.add_verb(pause);
+    api.add_verb(resume);
+    api.add_verb(wakeup);
+    api.add_verb(replug);
+    api.add_verb(phases);
+    api.add_verb(unlock);
+    api.add_verb(auth);
+    api.add_verb(session);
+    api.add_verb(log);
+    api.add_verb(status);
+    api.add_verb(history);
+    api.add_verb(limit);
+    api.add_verb(profile);
+    api.add_verb(delay);
+    api.add_verb(surplus);
 
     // init m4 firmware (set pwm-off and enable iec6185 event)
     for msg in [mk_pwm(&PwmState::Off, 0.0)?, mk_enable()?] {
@@ -469,5 +1209,16 @@ pub(crate) fn register(
         }
     }
 
-    Ok(())
+    ocpp_update(&state);
+
+    // energy manager should send a first limit before keep-alive timeout
+    ext_limit_watch(&state)?;
+    profile_start(&state)?;
+
+    // older firmware never answers, status then reports no version
+    if let Err(error) = handle.write(&mk_get_version()?) {
+        afb_log_msg!(Warning, None, "{}: firmware version request fail:{}", config.uid, error);
+    }
+
+    Ok(state)
 }
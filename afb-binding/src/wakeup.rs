@@ -0,0 +1,221 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * Reference: IEC 61851-1 Annex A.5.3 (EV wake-up)
+ *  some cars fall asleep in state B and ignore a later PWM. Waking them up is done
+ *  by moving CP to state E/F or removing PWM for a while, then restoring the duty.
+ */
+use std::rc::Rc;
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use rpmsg::prelude::*;
+use typesv4::prelude::*;
+
+#[derive(Clone, Copy)]
+pub(crate) struct WakeupConfig {
+    // PwmState::F (state E/F) or PwmState::Off (PWM removed)
+    pub state: PwmState,
+    pub duration: u32,
+    // how long we wait for CAR_REQUESTED_POWER once duty is restored
+    pub watch: u32,
+    // automatic trigger delay in state B (0=disable)
+    pub auto: u32,
+}
+
+impl WakeupConfig {
+    pub fn from_jsonc(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jwakeup = match jconf.optional::<JsoncObj>("wakeup")? {
+            Some(value) => value,
+            None => JsoncObj::new(),
+        };
+
+        let state = match jwakeup.default::<&'static str>("mode", "F")?.to_uppercase().as_str() {
+            "F" => PwmState::F,
+            "OFF" => PwmState::Off,
+            _ => return afb_error!("wakeup-config-invalid", "wakeup mode should be F|OFF"),
+        };
+
+        Ok(WakeupConfig {
+            state,
+            duration: jwakeup.default::<u32>("duration", 4000)?,
+            watch: jwakeup.default::<u32>("watch", 10000)?,
+            auto: jwakeup.default::<u32>("auto", 0)?,
+        })
+    }
+}
+
+struct WakeupTimerCtx {
+    state: Rc<EvseState>,
+    gen: u32,
+}
+
+// end of E/F or PWM-off phase, restore last duty and watch for car response
+fn wakeup_restore_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<WakeupTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.seq_gen.get() {
+        return Ok(());
+    }
+
+    {
+        let mut data = state.data.borrow_mut();
+        data.sequence = None;
+        data.wakeup_watch = true;
+    }
+    state.apply_pwm()?;
+
+    AfbTimer::new("wakeup-watch")
+        .set_period(state.wakeup.watch)
+        .set_decount(1)
+        .set_callback(wakeup_watch_cb)
+        .set_context(WakeupTimerCtx {
+            state: state.clone(),
+            gen: ctx.gen,
+        })
+        .start()?;
+    Ok(())
+}
+
+fn wakeup_watch_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<WakeupTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.seq_gen.get() {
+        return Ok(());
+    }
+
+    let watching = {
+        let mut data = state.data.borrow_mut();
+        let watching = data.wakeup_watch;
+        data.wakeup_watch = false;
+        watching
+    };
+
+    if watching {
+        afb_log_msg!(Warning, None, "{}: car did not answer wakeup", state.uid);
+        state.push(Iec6185Msg::WakeUp(false));
+    }
+    Ok(())
+}
+
+pub(crate) fn wakeup_start(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    {
+        let mut data = state.data.borrow_mut();
+        if !data.plugged || data.power_rqt {
+            return afb_error!(
+                "wakeup-invalid-state",
+                "wakeup requires a plugged car not requesting power (state B)"
+            );
+        }
+        if data.pause.is_some() || data.pwm_state != PwmState::On {
+            return afb_error!(
+                "wakeup-invalid-state",
+                "wakeup requires an active PWM (pwm:{:?} pause:{:?})",
+                data.pwm_state,
+                data.pause
+            );
+        }
+        if let Some(sequence) = data.sequence {
+            return afb_error!("wakeup-busy", "sequence:{:?} already running", sequence);
+        }
+        data.sequence = Some(EvseSequence::Wakeup);
+        data.wakeup_watch = false;
+        data.wakeup_tried = true;
+    }
+    let gen = state.seq_gen.get() + 1;
+    state.seq_gen.set(gen);
+
+    afb_log_msg!(
+        Notice,
+        None,
+        "{}: wakeup car pwm:{:?} for {}ms",
+        state.uid,
+        state.wakeup.state,
+        state.wakeup.duration
+    );
+    state.send_pwm(state.wakeup.state, 0.0)?;
+
+    AfbTimer::new("wakeup-restore")
+        .set_period(state.wakeup.duration)
+        .set_decount(1)
+        .set_callback(wakeup_restore_cb)
+        .set_context(WakeupTimerCtx {
+            state: state.clone(),
+            gen,
+        })
+        .start()?;
+    Ok(())
+}
+
+// called on CAR_REQUESTED_POWER, report a successful wakeup
+pub(crate) fn wakeup_check(state: &Rc<EvseState>) {
+    let watching = {
+        let mut data = state.data.borrow_mut();
+        let watching = data.wakeup_watch;
+        data.wakeup_watch = false;
+        watching
+    };
+
+    if watching {
+        state.push(Iec6185Msg::WakeUp(true));
+    }
+}
+
+fn wakeup_auto_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<WakeupTimerCtx>()?;
+    let state = &ctx.state;
+    if ctx.gen != state.seq_gen.get() {
+        return Ok(());
+    }
+
+    {
+        let data = state.data.borrow();
+        if data.wakeup_tried
+            || !data.plugged
+            || data.power_rqt
+            || data.pause.is_some()
+            || data.pwm_state != PwmState::On
+        {
+            return Ok(());
+        }
+    }
+    wakeup_start(state)
+}
+
+// arm automatic wakeup when car sits in state B with an active PWM
+pub(crate) fn wakeup_arm(state: &Rc<EvseState>) -> Result<(), AfbError> {
+    if state.wakeup.auto == 0 {
+        return Ok(());
+    }
+
+    {
+        let data = state.data.borrow();
+        if data.wakeup_tried || !data.plugged || data.power_rqt || data.pwm_state != PwmState::On {
+            return Ok(());
+        }
+    }
+
+    AfbTimer::new("wakeup-auto")
+        .set_period(state.wakeup.auto)
+        .set_decount(1)
+        .set_callback(wakeup_auto_cb)
+        .set_context(WakeupTimerCtx {
+            state: state.clone(),
+            gen: state.seq_gen.get(),
+        })
+        .start()?;
+    Ok(())
+}
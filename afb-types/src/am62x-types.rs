@@ -12,6 +12,150 @@
 use serde::{Deserialize, Serialize};
 use afbv4::prelude::*;
 
+AfbDataConverter!(pause_reason, PauseReason);
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PauseReason {
+    Scheduler,
+    Operator,
+    Overtemperature,
+    PhaseSwitch,
+    Unauthorized,
+    Budget,
+    Surplus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LockState {
+    Unknown,
+    Locking,
+    Locked,
+    Unlocking,
+    Unlocked,
+    Fault,
+}
+
+// why the last charging period ended
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StopReason {
+    Ev,
+    Paused,
+    Fault,
+    Deauthorized,
+    Unplugged,
+}
+
+// charging session from plug-in to unplug (timestamps in ms since epoch)
+AfbDataConverter!(session_info, SessionInfo);
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub connector: String,
+    pub plugged: u64,
+    pub authorized: Option<u64>,
+    pub power_start: Option<u64>,
+    pub stopped: Option<u64>,
+    pub unplugged: Option<u64>,
+    pub imax_offered: u32,
+    pub cable_imax: u32,
+    pub faults: Vec<String>,
+    pub stop_reason: Option<StopReason>,
+    // delivered energy (Wh), only with a connector meter
+    #[serde(default)]
+    pub energy: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectorState {
+    Idle,
+    Plugged,
+    Charging,
+    Paused,
+    Faulted,
+}
+
+// OCPP 1.6 ChargePointStatus (names as in OCPP spec)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OcppStatus {
+    Available,
+    Preparing,
+    Charging,
+    SuspendedEV,
+    SuspendedEVSE,
+    Finishing,
+    Faulted,
+    Unavailable,
+}
+
+// OCPP 2.0.1 ConnectorStatus (reservation is handled by OCPP binding)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OcppConnectorStatus {
+    Available,
+    Occupied,
+    Unavailable,
+    Faulted,
+}
+
+// OCPP 1.6 ChargePointErrorCode subset reachable from binding state
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OcppErrorCode {
+    NoError,
+    ConnectorLockFailure,
+    EVCommunicationError,
+    GroundFailure,
+    HighTemperature,
+    InternalError,
+    OverCurrentFailure,
+    PowerSwitchFailure,
+    OtherError,
+}
+
+AfbDataConverter!(ocpp_connector, OcppConnector);
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OcppConnector {
+    pub status: OcppStatus,
+    pub connector_status: OcppConnectorStatus,
+    pub error_code: OcppErrorCode,
+    // raw binding fault behind error_code
+    pub vendor_error: Option<String>,
+}
+
+// status verb snapshot (heartbeat_age in ms since last mcu heartbeat)
+AfbDataConverter!(evse_status, EvseStatus);
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EvseStatus {
+    pub connector: String,
+    pub state: ConnectorState,
+    pub plugged: bool,
+    pub power_rqt: bool,
+    pub cable_imax: u32,
+    pub pwm_state: String,
+    pub pwm_duty: f32,
+    pub power_allowed: bool,
+    pub relay_on: bool,
+    pub slac_state: String,
+    pub lock: LockState,
+    pub authorized: bool,
+    pub pause: Option<PauseReason>,
+    pub phases: u32,
+    // lowest active current limit (A)
+    pub limit: Option<u32>,
+    pub limit_failsafe: bool,
+    // remaining random delay (ms)
+    pub random_delay: Option<u64>,
+    // last meter reading (A per phase) and running session energy (Wh)
+    pub current: Option<Vec<f64>>,
+    pub energy: Option<f64>,
+    pub heartbeat_count: u32,
+    pub heartbeat_age: Option<u64>,
+    pub faults: Vec<String>,
+    pub firmware: Option<String>,
+    pub ocpp: OcppConnector,
+}
+
 AfbDataConverter!(iec6185_msg, Iec6185Msg);
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -21,11 +165,61 @@ pub enum Iec6185Msg {
     CableImax(u32),
     RelayOn(bool),
     Error(String),
+    Paused(PauseReason),
+    Resumed(PauseReason),
+    WakeUp(bool),
+    Replug(bool),
+    Phases(u32),
+    Locked(bool),
+    #[serde(rename = "lock-fault")]
+    LockFault(bool),
+    Authorized(bool),
+    #[serde(rename = "session-start")]
+    SessionStart(SessionInfo),
+    #[serde(rename = "session-update")]
+    SessionUpdate(SessionInfo),
+    #[serde(rename = "session-end")]
+    SessionEnd(SessionInfo),
+    Slac(String),
+    Heartbeat(u32),
+    Firmware(String),
+    Limit(u32),
+    #[serde(rename = "limit-failsafe")]
+    LimitFailsafe(bool),
+    // random delay started (s), 0 when done or cancelled
+    #[serde(rename = "random-delay")]
+    RandomDelay(u32),
+    // temperature derating active
+    Overtemp(bool),
+    Ocpp(OcppConnector),
 }
 
 
+// event envelope schema version, bump on any incompatible change
+pub const EVSE_EVENT_VERSION: u32 = 1;
+
+// published instead of raw Iec6185Msg when binding 'envelope' config is set
+// (seq: per event stream, 'iec' and each channel are numbered independently)
+// (ts: wall-clock ms since epoch, uptime: monotonic ms since binding start)
+AfbDataConverter!(evse_event, EvseEvent);
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EvseEvent {
+    pub seq: u64,
+    pub ts: u64,
+    pub uptime: u64,
+    pub connector: String,
+    pub version: u32,
+    pub iec: Option<String>,
+    pub msg: Iec6185Msg,
+}
+
 pub fn am62x_registers() -> Result <(), AfbError> {
     // add binding custom converter
+    pause_reason::register()?;
+    session_info::register()?;
+    ocpp_connector::register()?;
+    evse_status::register()?;
     iec6185_msg::register()?;
+    evse_event::register()?;
     Ok(())
 }
\ No newline at end of file
@@ -35,6 +35,9 @@ pub type SlacState= pbuf::SlacState;
 pub enum EventMsg {
     Evt(Iec61851Event),
     Heartbeat(),
+    Phases(u32),
+    Lock(bool),
+    Version(String),
     Err(AfbError),
 }
 
@@ -125,6 +128,52 @@ pub fn mk_slac(state: &SlacState) -> Result<Vec<u8>, AfbError> {
     }
 }
 
+pub fn mk_replug(duration: u32) -> Result<Vec<u8>, AfbError> {
+
+    let msg = pbuf::HighToLow {
+        message: Some(pbuf::high_to_low::Message::Replug(pbuf::Replug {duration})),
+    };
+    let mut buffer = Vec::with_capacity(msg.encoded_len());
+    match msg.encode(&mut buffer) {
+        Ok(()) => Ok(buffer),
+        Err(error) => afb_error!("encoding-replug-fail", "{}", error),
+    }
+}
+
+pub fn mk_phases(count: u32) -> Result<Vec<u8>, AfbError> {
+
+    let msg = pbuf::HighToLow {
+        message: Some(pbuf::high_to_low::Message::SetPhases(pbuf::SetPhases {count})),
+    };
+    let mut buffer = Vec::with_capacity(msg.encoded_len());
+    match msg.encode(&mut buffer) {
+        Ok(()) => Ok(buffer),
+        Err(error) => afb_error!("encoding-phases-fail", "{}", error),
+    }
+}
+
+pub fn mk_lock(lock: bool) -> Result<Vec<u8>, AfbError> {
+    let msg = pbuf::HighToLow {
+        message: Some(pbuf::high_to_low::Message::SetLock(lock)),
+    };
+    let mut buffer = Vec::with_capacity(msg.encoded_len());
+    match msg.encode(&mut buffer) {
+        Ok(()) => Ok(buffer),
+        Err(error) => afb_error!("encoding-lock-fail", "{}", error),
+    }
+}
+
+pub fn mk_get_version() -> Result<Vec<u8>, AfbError> {
+    let msg = pbuf::HighToLow {
+        message: Some(pbuf::high_to_low::Message::GetVersion(pbuf::Empty {})),
+    };
+    let mut buffer = Vec::with_capacity(msg.encoded_len());
+    match msg.encode(&mut buffer) {
+        Ok(()) => Ok(buffer),
+        Err(error) => afb_error!("encoding-version-fail", "{}", error),
+    }
+}
+
 // decode message from encoded buffer
 pub fn msg_uncode(buffer: &[u8]) -> EventMsg {
     match pbuf::LowToHigh::decode(buffer) {
@@ -133,6 +182,9 @@ pub fn msg_uncode(buffer: &[u8]) -> EventMsg {
             None => EventMsg::Err(AfbError::new("decoding-buffer-empty", 0, "no data to decode")),
             Some(msg) => match msg {
                 pbuf::low_to_high::Message::Heartbeat(_) => EventMsg::Heartbeat(),
+                pbuf::low_to_high::Message::Phases(value) => EventMsg::Phases(value.count),
+                pbuf::low_to_high::Message::Lock(value) => EventMsg::Lock(value),
+                pbuf::low_to_high::Message::Version(value) => EventMsg::Version(value.version),
                 pbuf::low_to_high::Message::Event(value) => match Iec61851Event::try_from(value) {
                     Ok(iec) => EventMsg::Evt(iec),
                     Err(error) => EventMsg::Err(AfbError::new(
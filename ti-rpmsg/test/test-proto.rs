@@ -38,6 +38,55 @@ fn check_heartbeat() {
     }
 }
 
+#[test]
+fn check_replug_event() {
+    let buffer: [u8; 2] = [0x08, 0x0F]; // low_to_high EVSE_REPLUG_STARTED
+
+    match msg_uncode(&buffer) {
+        EventMsg::Evt(Iec61851Event::EvseReplugStarted) => {
+            println!("OK replug started")
+        }
+        _ => panic!("fail to decode replug event"),
+    }
+}
+
+#[test]
+fn check_phases_confirm() {
+    let buffer: [u8; 4] = [0x1A, 0x02, 0x08, 0x03]; // low_to_high phases count=3
+
+    match msg_uncode(&buffer) {
+        EventMsg::Phases(3) => {
+            println!("OK phases confirmed")
+        }
+        _ => panic!("fail to decode phases confirmation"),
+    }
+}
+
+#[test]
+fn check_lock_feedback() {
+    let buffer: [u8; 2] = [0x20, 0x01]; // low_to_high lock=true
+
+    match msg_uncode(&buffer) {
+        EventMsg::Lock(true) => {
+            println!("OK lock feedback")
+        }
+        _ => panic!("fail to decode lock feedback"),
+    }
+}
+
+#[test]
+fn check_firmware_version() {
+    let buffer: [u8; 7] = [0x2A, 0x05, 0x0A, 0x03, 0x31, 0x2E, 0x32]; // low_to_high version="1.2"
+
+    match msg_uncode(&buffer) {
+        EventMsg::Version(version) => {
+            assert_eq!(version, "1.2");
+            println!("OK firmware version={}", version)
+        }
+        _ => panic!("fail to decode firmware version"),
+    }
+}
+
 #[test]
 fn capi_get_heartbeat() {
     let src: [u8; 2] = [0x12, 0x0]; // low_to_high heartbeat